@@ -0,0 +1,262 @@
+use clap::ValueEnum;
+use notify::EventKind;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Above this many bytes the paths go to a temp file instead of the environment
+const MAX_ENV_BYTES: usize = 64 * 1024;
+
+// Numbers the change list files created by this process
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+    Renamed,
+}
+
+impl ChangeKind {
+    pub fn from_event(kind: &EventKind) -> Self {
+        match kind {
+            EventKind::Create(_) => ChangeKind::Created,
+            EventKind::Remove(_) => ChangeKind::Removed,
+            EventKind::Modify(notify::event::ModifyKind::Name(_)) => ChangeKind::Renamed,
+            _ => ChangeKind::Modified,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Renamed => "renamed",
+        }
+    }
+
+    fn env_var(self) -> &'static str {
+        match self {
+            ChangeKind::Created => "WATCHER_CREATED",
+            ChangeKind::Modified => "WATCHER_MODIFIED",
+            ChangeKind::Removed => "WATCHER_REMOVED",
+            ChangeKind::Renamed => "WATCHER_RENAMED",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PathSeparator {
+    /// One path per line
+    Newline,
+    /// NUL-terminated paths, for `xargs -0`
+    Nul,
+}
+
+// Paths changed since the last run, with the most telling kind of change for each
+#[derive(Clone, Debug, Default)]
+pub struct ChangeSet {
+    paths: BTreeMap<PathBuf, ChangeKind>,
+}
+
+impl ChangeSet {
+    pub fn add(&mut self, path: &Path, kind: ChangeKind) {
+        match self.paths.get(path) {
+            // A file created and then written to is still new
+            Some(ChangeKind::Created) if kind == ChangeKind::Modified => {}
+            _ => {
+                self.paths.insert(path.to_path_buf(), kind);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    // The subset of changes whose path satisfies `predicate`
+    pub fn filtered(&self, mut predicate: impl FnMut(&Path) -> bool) -> ChangeSet {
+        let paths = self
+            .paths
+            .iter()
+            .filter(|(path, _)| predicate(path))
+            .map(|(path, kind)| (path.clone(), *kind))
+            .collect();
+        ChangeSet { paths }
+    }
+
+    // The same changes with paths below `base` made relative to it
+    pub fn relative_to(&self, base: &Path) -> ChangeSet {
+        let paths = self
+            .paths
+            .iter()
+            .map(|(path, kind)| {
+                let relative = path.strip_prefix(base).unwrap_or(path);
+                (relative.to_path_buf(), *kind)
+            })
+            .collect();
+        ChangeSet { paths }
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths.keys().map(PathBuf::as_path)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, ChangeKind)> {
+        self.paths
+            .iter()
+            .map(|(path, kind)| (path.as_path(), *kind))
+    }
+
+    fn paths_of(&self, kind: ChangeKind) -> impl Iterator<Item = &Path> {
+        self.iter()
+            .filter(move |(_, k)| *k == kind)
+            .map(|(path, _)| path)
+    }
+
+    pub fn to_bytes(&self, separator: PathSeparator) -> Vec<u8> {
+        let terminator = match separator {
+            PathSeparator::Newline => b'\n',
+            PathSeparator::Nul => b'\0',
+        };
+        let mut bytes = Vec::new();
+        for path in self.paths() {
+            bytes.extend_from_slice(path.as_os_str().as_encoded_bytes());
+            bytes.push(terminator);
+        }
+        bytes
+    }
+
+    // Exposes the paths as WATCHER_* variables, or through WATCHER_CHANGED_FILE
+    // when there are too many for the environment or one contains the list
+    // separator. That file lasts as long as the returned guard.
+    pub fn apply_env(&self, command: &mut Command) -> io::Result<Option<ChangesFile>> {
+        command.env("WATCHER_CHANGED_COUNT", self.len().to_string());
+        let all = join_paths(self.paths()).filter(|all| all.len() <= MAX_ENV_BYTES);
+        let Some(all) = all else {
+            let file = ChangesFile::create(&self.to_bytes(PathSeparator::Newline))?;
+            command.env("WATCHER_CHANGED_FILE", &file.0);
+            return Ok(Some(file));
+        };
+
+        command.env("WATCHER_CHANGED_PATHS", all);
+        for kind in [
+            ChangeKind::Created,
+            ChangeKind::Modified,
+            ChangeKind::Removed,
+            ChangeKind::Renamed,
+        ] {
+            // Every path could be joined above, so these can be too
+            let paths = join_paths(self.paths_of(kind)).unwrap_or_default();
+            command.env(kind.env_var(), paths);
+        }
+        Ok(None)
+    }
+}
+
+// A temp file listing changed paths for one job, removed when dropped
+#[derive(Debug)]
+pub struct ChangesFile(PathBuf);
+
+impl ChangesFile {
+    // Always creates a new file, readable only by the user, so nothing already at
+    // the name in a shared temp directory (such as a symlink) is ever written to
+    fn create(contents: &[u8]) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        for _ in 0..100 {
+            let number = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+            let name = format!("watcher-{}-{}-changes", std::process::id(), number);
+            let path = std::env::temp_dir().join(name);
+            match options.open(&path) {
+                Ok(mut file) => {
+                    let changes_file = ChangesFile(path);
+                    file.write_all(contents)?;
+                    return Ok(changes_file);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "no free name for the changed paths file",
+        ))
+    }
+}
+
+impl Drop for ChangesFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Joins paths like $PATH, or None if one contains the separator itself
+fn join_paths<'a>(paths: impl Iterator<Item = &'a Path>) -> Option<OsString> {
+    std::env::join_paths(paths).ok()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn env(command: &Command, name: &str) -> Option<String> {
+        command
+            .get_envs()
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| value)
+            .map(|value| value.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn lists_paths_in_the_environment() {
+        let mut changes = ChangeSet::default();
+        changes.add(Path::new("/p/a.rs"), ChangeKind::Modified);
+        changes.add(Path::new("/p/b.rs"), ChangeKind::Created);
+
+        let mut command = Command::new("true");
+        assert!(changes.apply_env(&mut command).unwrap().is_none());
+        assert_eq!(
+            env(&command, "WATCHER_CHANGED_PATHS").unwrap(),
+            "/p/a.rs:/p/b.rs"
+        );
+        assert_eq!(env(&command, "WATCHER_CREATED").unwrap(), "/p/b.rs");
+        assert_eq!(env(&command, "WATCHER_CHANGED_COUNT").unwrap(), "2");
+        assert_eq!(env(&command, "WATCHER_CHANGED_FILE"), None);
+    }
+
+    #[test]
+    fn lists_paths_containing_the_separator_in_a_file() {
+        let mut changes = ChangeSet::default();
+        changes.add(Path::new("/p/a:b.rs"), ChangeKind::Modified);
+        changes.add(Path::new("/p/c.rs"), ChangeKind::Modified);
+
+        let mut command = Command::new("true");
+        let file = changes.apply_env(&mut command).unwrap().unwrap();
+        assert_eq!(env(&command, "WATCHER_CHANGED_PATHS"), None);
+        assert_eq!(env(&command, "WATCHER_CHANGED_COUNT").unwrap(), "2");
+        assert_eq!(fs::read_to_string(&file.0).unwrap(), "/p/a:b.rs\n/p/c.rs\n");
+
+        let path = file.0.clone();
+        drop(file);
+        assert!(!path.exists());
+    }
+}
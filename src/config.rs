@@ -0,0 +1,199 @@
+use crate::changes::PathSeparator;
+use crate::debounce::Strategy;
+use crate::duration::parse_duration;
+use crate::process::{Signal, StopSequence};
+use crate::shell::ShellProfile;
+use crate::task::{BusyPolicy, TaskCommand};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const CONFIG_FILE_NAME: &str = "watcher.toml";
+
+// Contents of a watcher.toml file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub tasks: BTreeMap<String, TaskConfig>,
+    #[serde(default)]
+    pub shells: BTreeMap<String, ShellProfile>,
+}
+
+// A `[tasks.NAME]` table. The command line overrides these, and unset fields fall
+// back to defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TaskConfig {
+    pub directories: Option<Vec<RootConfig>>,
+    pub command: Option<TaskCommand>,
+    pub on: Option<Vec<Route>>,
+    pub extensions: Option<Vec<String>>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub no_ignore: Option<bool>,
+    pub on_busy: Option<BusyPolicy>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub busy_signal: Option<Signal>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    pub stop_sequence: Option<StopSequence>,
+    pub strategy: Option<Strategy>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    pub debounce: Option<Duration>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    pub window: Option<Duration>,
+    #[serde(default, deserialize_with = "duration_opt")]
+    pub max_wait: Option<Duration>,
+    pub stdin_paths: Option<PathSeparator>,
+    pub per_file: Option<bool>,
+    pub jobs: Option<usize>,
+    pub timestamps: Option<bool>,
+    pub initial_run: Option<bool>,
+    pub workdir: Option<PathBuf>,
+    pub relative_to: Option<PathBuf>,
+    pub depends_on: Option<Vec<String>>,
+    pub then: Option<Vec<String>>,
+}
+
+// An entry of `directories`: a directory or file path, or a table such as
+// `{ path = "proto", recursive = false }` to leave out subdirectories
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum RootConfig {
+    Path(PathBuf),
+    Table {
+        path: PathBuf,
+        #[serde(default = "default_recursive")]
+        recursive: bool,
+    },
+}
+
+impl RootConfig {
+    pub fn path(&self) -> &Path {
+        match self {
+            RootConfig::Path(path) | RootConfig::Table { path, .. } => path,
+        }
+    }
+
+    pub fn recursive(&self) -> bool {
+        match self {
+            RootConfig::Path(_) => true,
+            RootConfig::Table { recursive, .. } => *recursive,
+        }
+    }
+
+    fn path_mut(&mut self) -> &mut PathBuf {
+        match self {
+            RootConfig::Path(path) | RootConfig::Table { path, .. } => path,
+        }
+    }
+}
+
+fn default_recursive() -> bool {
+    true
+}
+
+// Runs `command` for the changes whose path matches `pattern`, given as
+// `[[tasks.NAME.on]]` tables or `--on 'pattern=command'`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub pattern: String,
+    pub command: TaskCommand,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        match rule.split_once('=') {
+            Some((pattern, command)) if !pattern.is_empty() && !command.is_empty() => Ok(Route {
+                pattern: pattern.to_string(),
+                command: TaskCommand::Shell(command.to_string()),
+            }),
+            _ => Err(format!("expected 'glob=command', got: {}", rule)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    path: PathBuf,
+    message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let error = |message: String| ConfigError {
+            path: path.to_path_buf(),
+            message,
+        };
+
+        let text = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        let mut config: ConfigFile = toml::from_str(&text).map_err(|e| error(e.to_string()))?;
+
+        // Paths are relative to the file's own location, which is also the default directory
+        let base = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        for task in config.tasks.values_mut() {
+            let directories = task
+                .directories
+                .get_or_insert_with(|| vec![RootConfig::Path(PathBuf::new())]);
+            for directory in directories {
+                let path = directory.path_mut();
+                *path = base.join(&*path);
+            }
+            for path in [&mut task.workdir, &mut task.relative_to]
+                .into_iter()
+                .flatten()
+            {
+                *path = base.join(&*path);
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+// Looks for watcher.toml in `start` and each of its parents
+pub fn find_config(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+fn from_str_opt<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+fn duration_opt<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| parse_duration(&value).map_err(de::Error::custom))
+        .transpose()
+}
@@ -0,0 +1,220 @@
+use crate::changes::{ChangeKind, ChangeSet};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Run once changes have been quiet for the debounce period
+    Trailing,
+    /// Run on the first change, then ignore changes for the debounce period
+    Leading,
+    /// Run at most once per debounce period, picking up changes made meanwhile
+    Throttle,
+}
+
+// Changes since the last run, and when they happened, deciding when the next run starts
+pub struct EventBuffer {
+    // Times of the recent events, no further apart than `window` from the latest
+    events: VecDeque<Instant>,
+    changes: ChangeSet,
+    window: Duration,
+    strategy: Strategy,
+    period: Duration,
+    // First event since the last trigger, kept even after it leaves the window
+    batch_start: Option<Instant>,
+    last_trigger: Option<Instant>,
+}
+
+impl EventBuffer {
+    pub fn new(window: Duration, strategy: Strategy, period: Duration) -> Self {
+        Self {
+            events: VecDeque::new(),
+            changes: ChangeSet::default(),
+            window,
+            strategy,
+            period,
+            batch_start: None,
+            last_trigger: None,
+        }
+    }
+
+    fn since_last_trigger(&self, now: Instant) -> Option<Duration> {
+        self.last_trigger.map(|time| now.duration_since(time))
+    }
+
+    pub fn add_event(&mut self, now: Instant, paths: &[&Path], kind: ChangeKind) {
+        if self.strategy == Strategy::Leading
+            && self
+                .since_last_trigger(now)
+                .is_some_and(|since| since < self.period)
+        {
+            return;
+        }
+
+        // Remove old events outside the window
+        while let Some(time) = self.events.front() {
+            if now.duration_since(*time) > self.window {
+                self.events.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.events.push_back(now);
+        self.batch_start.get_or_insert(now);
+        for path in paths {
+            self.changes.add(path, kind);
+        }
+    }
+
+    pub fn should_trigger(&self, now: Instant) -> bool {
+        let Some(last_event) = self.events.back() else {
+            return false;
+        };
+
+        match self.strategy {
+            // A burst of changes needs the longer of the period and the window to settle
+            Strategy::Trailing => {
+                let quiet = if self.events.len() > 1 {
+                    self.period.max(self.window)
+                } else {
+                    self.period
+                };
+                now.duration_since(*last_event) >= quiet
+            }
+            Strategy::Leading => true,
+            Strategy::Throttle => self
+                .since_last_trigger(now)
+                .is_none_or(|since| since >= self.period),
+        }
+    }
+
+    // True once the current batch has been pending for longer than `max_wait`,
+    // even if changes keep arriving
+    pub fn is_overdue(&self, max_wait: Duration, now: Instant) -> bool {
+        self.batch_start
+            .is_some_and(|start| now.duration_since(start) >= max_wait)
+    }
+
+    // Starts a new batch after the command has been triggered, returning the
+    // paths changed in the finished one
+    pub fn mark_triggered(&mut self, now: Instant) -> ChangeSet {
+        self.events.clear();
+        self.batch_start = None;
+        self.last_trigger = Some(now);
+        std::mem::take(&mut self.changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_millis(500);
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn change(buffer: &mut EventBuffer, now: Instant) {
+        buffer.add_event(now, &[Path::new("/a.txt")], ChangeKind::Modified);
+    }
+
+    #[test]
+    fn trailing_waits_for_a_quiet_period() {
+        let start = Instant::now();
+        let mut buffer = EventBuffer::new(PERIOD, Strategy::Trailing, PERIOD);
+        assert!(!buffer.should_trigger(start));
+
+        change(&mut buffer, start);
+        change(&mut buffer, start + ms(300));
+        assert!(!buffer.should_trigger(start + ms(600)));
+        assert!(buffer.should_trigger(start + ms(800)));
+        assert_eq!(buffer.mark_triggered(start + ms(800)).len(), 1);
+        assert!(!buffer.should_trigger(start + ms(2000)));
+    }
+
+    #[test]
+    fn trailing_lets_bursts_settle_for_the_window() {
+        let start = Instant::now();
+        let mut buffer = EventBuffer::new(ms(2000), Strategy::Trailing, PERIOD);
+
+        // A single change runs after the period
+        change(&mut buffer, start);
+        assert!(buffer.should_trigger(start + ms(500)));
+        buffer.mark_triggered(start + ms(500));
+
+        // Changes within the window of each other wait for the window
+        let burst = start + ms(5000);
+        change(&mut buffer, burst);
+        change(&mut buffer, burst + ms(1500));
+        assert!(!buffer.should_trigger(burst + ms(2000)));
+        assert!(!buffer.should_trigger(burst + ms(3400)));
+        assert!(buffer.should_trigger(burst + ms(3500)));
+        buffer.mark_triggered(burst + ms(3500));
+
+        // Changes further apart than the window are no burst
+        let apart = start + ms(10000);
+        change(&mut buffer, apart);
+        change(&mut buffer, apart + ms(2100));
+        assert!(buffer.should_trigger(apart + ms(2600)));
+    }
+
+    #[test]
+    fn leading_suppresses_changes_inside_the_period() {
+        let start = Instant::now();
+        let mut buffer = EventBuffer::new(ms(1000), Strategy::Leading, PERIOD);
+
+        change(&mut buffer, start);
+        assert!(buffer.should_trigger(start));
+        buffer.mark_triggered(start);
+
+        change(&mut buffer, start + ms(200));
+        assert!(!buffer.should_trigger(start + ms(200)));
+        assert!(!buffer.should_trigger(start + ms(1000)));
+
+        change(&mut buffer, start + ms(600));
+        assert!(buffer.should_trigger(start + ms(600)));
+    }
+
+    #[test]
+    fn throttle_spaces_triggers_at_least_a_period_apart() {
+        let start = Instant::now();
+        let mut buffer = EventBuffer::new(ms(1000), Strategy::Throttle, PERIOD);
+
+        change(&mut buffer, start);
+        assert!(buffer.should_trigger(start));
+        buffer.mark_triggered(start);
+
+        // Changes made meanwhile are kept for the next run
+        change(&mut buffer, start + ms(100));
+        assert!(!buffer.should_trigger(start + ms(100)));
+        assert!(!buffer.should_trigger(start + ms(499)));
+        assert!(buffer.should_trigger(start + ms(500)));
+        assert_eq!(buffer.mark_triggered(start + ms(500)).len(), 1);
+    }
+
+    #[test]
+    fn max_wait_fires_while_changes_keep_arriving() {
+        let start = Instant::now();
+        let mut buffer = EventBuffer::new(ms(1000), Strategy::Trailing, PERIOD);
+        let max_wait = ms(2000);
+
+        let mut now = start;
+        while now < start + ms(2000) {
+            change(&mut buffer, now);
+            assert!(!buffer.should_trigger(now));
+            assert!(!buffer.is_overdue(max_wait, now));
+            now += ms(400);
+        }
+        change(&mut buffer, now);
+        assert!(!buffer.should_trigger(now));
+        assert!(buffer.is_overdue(max_wait, now));
+
+        buffer.mark_triggered(now);
+        assert!(!buffer.is_overdue(max_wait, now + ms(5000)));
+    }
+}
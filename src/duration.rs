@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+// Parses human-readable durations such as `250ms`, `2s`, `1.5s` or `1m30s`.
+// A bare number is taken as milliseconds.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("empty duration".to_string());
+    }
+    if let Ok(millis) = input.parse::<u64>() {
+        return Ok(Duration::from_millis(millis));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = input;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .ok_or_else(|| format!("missing unit in duration: {}", input))?;
+        let unit_len = rest[number_len..]
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len() - number_len);
+
+        let number: f64 = rest[..number_len]
+            .parse()
+            .map_err(|_| format!("invalid duration: {}", input))?;
+        let unit_secs = match &rest[number_len..number_len + unit_len] {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            unit => return Err(format!("unknown unit '{}' in duration: {}", unit, input)),
+        };
+
+        total = Duration::try_from_secs_f64(number * unit_secs)
+            .ok()
+            .and_then(|duration| total.checked_add(duration))
+            .ok_or_else(|| format!("duration too long: {}", input))?;
+        rest = &rest[number_len + unit_len..];
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("3m"), Ok(Duration::from_secs(180)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn adds_up_components() {
+        assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 1s500ms "), Ok(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn takes_a_bare_number_as_milliseconds() {
+        assert_eq!(parse_duration("750"), Ok(Duration::from_millis(750)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert_eq!(parse_duration(""), Err("empty duration".to_string()));
+        assert_eq!(
+            parse_duration("1.5"),
+            Err("missing unit in duration: 1.5".to_string())
+        );
+        assert_eq!(
+            parse_duration("5d"),
+            Err("unknown unit 'd' in duration: 5d".to_string())
+        );
+        assert_eq!(
+            parse_duration("1..2s"),
+            Err("invalid duration: 1..2s".to_string())
+        );
+        assert!(parse_duration("s").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_too_long_to_represent() {
+        let error = "duration too long: 99999999999999999999h".to_string();
+        assert_eq!(parse_duration("99999999999999999999h"), Err(error));
+        let total = format!("{}s{}s", u64::MAX / 2 + 1, u64::MAX / 2 + 1);
+        assert!(parse_duration(&total).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use std::path::{Path, PathBuf};
+
+// A single include/exclude glob. Patterns starting with `!` negate a previous match.
+struct Rule {
+    matcher: GlobMatcher,
+    // For `dir/**` patterns, matches `dir` itself so whole trees can be skipped
+    subtree: Option<GlobMatcher>,
+    negated: bool,
+}
+
+// Ordered list of glob rules where the last matching rule wins, like .gitignore
+struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    fn new(patterns: &[String]) -> Result<Self, globset::Error> {
+        let rules = patterns
+            .iter()
+            .map(|pattern| {
+                let (negated, pattern) = match pattern.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, pattern.as_str()),
+                };
+                let matcher = compile_glob(pattern)?.compile_matcher();
+                let subtree = match pattern.strip_suffix("/**") {
+                    Some(prefix) => Some(compile_glob(prefix)?.compile_matcher()),
+                    None => None,
+                };
+                Ok(Rule {
+                    matcher,
+                    subtree,
+                    negated,
+                })
+            })
+            .collect::<Result<Vec<_>, globset::Error>>()?;
+
+        Ok(Self { rules })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // None if no rule matched, otherwise whether the last matching rule was positive
+    fn matches(&self, relative: &Path) -> Option<bool> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matcher.is_match(relative))
+            .map(|rule| !rule.negated)
+    }
+
+    // Whether every path below `dir` is matched, i.e. no later negation could re-include one
+    fn covers_dir(&self, relative: &Path) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .take_while(|rule| !rule.negated)
+            .any(|rule| rule.subtree.as_ref().is_some_and(|m| m.is_match(relative)))
+    }
+}
+
+pub fn compile_glob(pattern: &str) -> Result<Glob, globset::Error> {
+    // `*` stays within a path component; use `**` to cross directories
+    GlobBuilder::new(pattern.trim_start_matches("./"))
+        .literal_separator(true)
+        .backslash_escape(true)
+        .build()
+}
+
+// A watched path: a directory with or without its subdirectories, or a single file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchRoot {
+    pub path: PathBuf,
+    pub recursive: bool,
+    pub is_file: bool,
+}
+
+impl WatchRoot {
+    // The directory to watch and resolve relative paths against; a file's parent
+    pub fn dir(&self) -> &Path {
+        match self.path.parent() {
+            Some(parent) if self.is_file => parent,
+            _ => &self.path,
+        }
+    }
+
+    // Path relative to dir(), if the root includes it
+    fn relative<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        if self.is_file && path != self.path {
+            return None;
+        }
+        let relative = path.strip_prefix(self.dir()).ok()?;
+        if !self.recursive && relative.components().count() > 1 {
+            return None;
+        }
+        Some(relative)
+    }
+}
+
+// Decides whether a changed path should count towards a trigger
+pub struct PathFilter {
+    roots: Vec<WatchRoot>,
+    extensions: Vec<String>,
+    includes: RuleSet,
+    excludes: RuleSet,
+}
+
+impl PathFilter {
+    pub fn new(
+        roots: &[WatchRoot],
+        extensions: &[String],
+        includes: &[String],
+        excludes: &[String],
+    ) -> Result<Self, globset::Error> {
+        Ok(Self {
+            roots: roots.to_vec(),
+            extensions: extensions.to_vec(),
+            includes: RuleSet::new(includes)?,
+            excludes: RuleSet::new(excludes)?,
+        })
+    }
+
+    // Path relative to the watched root containing it, if any
+    pub fn relative<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        self.roots.iter().find_map(|root| root.relative(path))
+    }
+
+    pub fn covers(&self, path: &Path) -> bool {
+        self.relative(path).is_some()
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        if !has_matching_extension(path, &self.extensions) {
+            return false;
+        }
+
+        let Some(relative) = self.relative(path) else {
+            return false;
+        };
+
+        if !self.includes.is_empty() && self.includes.matches(relative) != Some(true) {
+            return false;
+        }
+
+        self.excludes.matches(relative) != Some(true)
+    }
+
+    // Whether an exclude glob rules out everything below this directory
+    pub fn excludes_dir(&self, dir: &Path) -> bool {
+        self.relative(dir)
+            .is_some_and(|relative| self.excludes.covers_dir(relative))
+    }
+}
+
+fn has_matching_extension(path: &Path, extensions: &[String]) -> bool {
+    if extensions.is_empty() {
+        return true;
+    }
+
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.iter().any(|e| e == ext))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(patterns: &[&str]) -> RuleSet {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        RuleSet::new(&patterns).unwrap()
+    }
+
+    fn filter(includes: &[&str], excludes: &[&str]) -> PathFilter {
+        let strings =
+            |patterns: &[&str]| -> Vec<String> { patterns.iter().map(|p| p.to_string()).collect() };
+        let root = WatchRoot {
+            path: PathBuf::from("/project"),
+            recursive: true,
+            is_file: false,
+        };
+        PathFilter::new(&[root], &[], &strings(includes), &strings(excludes)).unwrap()
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        let set = rules(&["*.log", "!keep.log", "keep.log"]);
+        assert_eq!(set.matches(Path::new("debug.log")), Some(true));
+        assert_eq!(set.matches(Path::new("keep.log")), Some(true));
+        assert_eq!(set.matches(Path::new("main.rs")), None);
+
+        let set = rules(&["*.log", "!keep.log"]);
+        assert_eq!(set.matches(Path::new("keep.log")), Some(false));
+    }
+
+    #[test]
+    fn star_stays_within_a_component() {
+        let set = rules(&["src/*.rs", "./docs/**"]);
+        assert_eq!(set.matches(Path::new("src/main.rs")), Some(true));
+        assert_eq!(set.matches(Path::new("src/bin/tool.rs")), None);
+        assert_eq!(set.matches(Path::new("docs/a/b.md")), Some(true));
+    }
+
+    #[test]
+    fn covers_dir_only_without_a_later_negation() {
+        let set = rules(&["target/**"]);
+        assert!(set.covers_dir(Path::new("target")));
+        assert!(!set.covers_dir(Path::new("src")));
+
+        let set = rules(&["target/**", "!target/keep.txt"]);
+        assert!(!set.covers_dir(Path::new("target")));
+    }
+
+    #[test]
+    fn filter_applies_includes_then_excludes() {
+        let filter = filter(&["src/**"], &["src/generated/**"]);
+        assert!(filter.matches(Path::new("/project/src/main.rs")));
+        assert!(!filter.matches(Path::new("/project/README.md")));
+        assert!(!filter.matches(Path::new("/project/src/generated/api.rs")));
+        assert!(!filter.matches(Path::new("/elsewhere/src/main.rs")));
+        assert!(filter.excludes_dir(Path::new("/project/src/generated")));
+    }
+
+    #[test]
+    fn shallow_and_file_roots_limit_relative_paths() {
+        let shallow = WatchRoot {
+            path: PathBuf::from("/project"),
+            recursive: false,
+            is_file: false,
+        };
+        assert_eq!(
+            shallow.relative(Path::new("/project/a.txt")),
+            Some(Path::new("a.txt"))
+        );
+        assert_eq!(shallow.relative(Path::new("/project/src/a.txt")), None);
+
+        let file = WatchRoot {
+            path: PathBuf::from("/project/Cargo.toml"),
+            recursive: true,
+            is_file: true,
+        };
+        assert_eq!(file.dir(), Path::new("/project"));
+        assert_eq!(
+            file.relative(Path::new("/project/Cargo.toml")),
+            Some(Path::new("Cargo.toml"))
+        );
+        assert_eq!(file.relative(Path::new("/project/Cargo.lock")), None);
+    }
+}
@@ -0,0 +1,153 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+// Per-directory ignore files, highest precedence first
+const IGNORE_FILES: [&str; 3] = [".watcherignore", ".ignore", ".gitignore"];
+
+// Repository (or watched directory, outside of git) whose ignore files apply
+struct Base {
+    path: PathBuf,
+    // .git/info/exclude and the global git excludes
+    excludes: Vec<Gitignore>,
+}
+
+impl Base {
+    fn new(root: &Path) -> Self {
+        let git_dir = find_git_dir(root);
+        let path = git_dir
+            .as_ref()
+            .and_then(|dir| dir.parent())
+            .unwrap_or(root)
+            .to_path_buf();
+
+        let mut excludes = Vec::new();
+        if let Some(git_dir) = &git_dir {
+            let mut builder = GitignoreBuilder::new(&path);
+            if builder.add(git_dir.join("info").join("exclude")).is_none() {
+                if let Ok(exclude) = builder.build() {
+                    excludes.push(exclude);
+                }
+            }
+        }
+
+        let (global, err) = GitignoreBuilder::new(&path).build_global();
+        if let Some(e) = err {
+            eprintln!("\x1b[31mFailed to read global git excludes: {}\x1b[0m", e);
+        }
+        excludes.push(global);
+
+        Self { path, excludes }
+    }
+}
+
+// Honors .gitignore/.ignore/.watcherignore files between the repository root
+// and each changed path, plus .git/info/exclude and the global git excludes
+pub struct IgnoreFilter {
+    bases: Vec<Base>,
+    // Lazily loaded matchers for each directory, in IGNORE_FILES order
+    dirs: HashMap<PathBuf, Vec<Gitignore>>,
+}
+
+impl IgnoreFilter {
+    pub fn new(roots: &[PathBuf]) -> Self {
+        let mut bases: Vec<Base> = Vec::new();
+        for root in roots {
+            let base = Base::new(root);
+            if !bases.iter().any(|b| b.path == base.path) {
+                bases.push(base);
+            }
+        }
+
+        Self {
+            bases,
+            dirs: HashMap::new(),
+        }
+    }
+
+    // The innermost base containing `path`
+    fn base_index(&self, path: &Path) -> Option<usize> {
+        (0..self.bases.len())
+            .filter(|&i| path.starts_with(&self.bases[i].path))
+            .max_by_key(|&i| self.bases[i].path.components().count())
+    }
+
+    pub fn is_ignored(&mut self, path: &Path) -> bool {
+        let Some(base_index) = self.base_index(path) else {
+            return false;
+        };
+        let base = self.bases[base_index].path.clone();
+        let relative = path.strip_prefix(&base).unwrap_or(path);
+        if relative
+            .components()
+            .any(|c| c == Component::Normal(".git".as_ref()))
+        {
+            return true;
+        }
+
+        let is_dir = path.is_dir();
+
+        // The closest directory's ignore files win over those further up
+        let mut dir = path.parent();
+        while let Some(current) = dir {
+            if !current.starts_with(&base) {
+                break;
+            }
+            for matcher in self.matchers_for(current) {
+                match matcher.matched_path_or_any_parents(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+            dir = current.parent();
+        }
+
+        self.bases[base_index].excludes.iter().any(|exclude| {
+            matches!(
+                exclude.matched_path_or_any_parents(path, is_dir),
+                Match::Ignore(_)
+            )
+        })
+    }
+
+    fn matchers_for(&mut self, dir: &Path) -> &[Gitignore] {
+        self.dirs.entry(dir.to_path_buf()).or_insert_with(|| {
+            IGNORE_FILES
+                .iter()
+                .map(|name| dir.join(name))
+                .filter(|file| file.is_file())
+                .filter_map(|file| {
+                    let (matcher, err) = Gitignore::new(&file);
+                    if let Some(e) = err {
+                        eprintln!("\x1b[31mFailed to parse {:?}: {}\x1b[0m", file, e);
+                    }
+                    (!matcher.is_empty()).then_some(matcher)
+                })
+                .collect()
+        })
+    }
+
+    // Drops cached rules when an ignore file changes so it is re-read on next use.
+    // Returns whether the path was an ignore file.
+    pub fn reload_if_ignore_file(&mut self, path: &Path) -> bool {
+        let is_ignore_file = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| IGNORE_FILES.contains(&name));
+
+        if is_ignore_file {
+            if let Some(dir) = path.parent() {
+                self.dirs.remove(dir);
+            }
+        }
+        is_ignore_file
+    }
+}
+
+fn find_git_dir(root: &Path) -> Option<PathBuf> {
+    root.ancestors()
+        .map(|dir| dir.join(".git"))
+        .find(|git_dir| git_dir.exists())
+}
@@ -1,87 +1,430 @@
-use clap::Parser;
-use notify::{EventKind, RecursiveMode, Watcher};
-use std::collections::VecDeque;
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+mod changes;
+mod config;
+mod debounce;
+mod duration;
+mod filter;
+mod ignores;
+mod output;
+mod pipeline;
+mod process;
+mod run;
+mod shell;
+mod task;
+mod template;
+mod watch;
+
+use changes::{ChangeKind, PathSeparator};
+use clap::{CommandFactory, Parser};
+use config::{ConfigFile, Route, TaskConfig};
+use debounce::Strategy;
+use duration::parse_duration;
+use filter::WatchRoot;
+use ignores::IgnoreFilter;
+use notify::EventKind;
+use notify::RecursiveMode;
+use pipeline::Stage;
+use process::{Signal, StopSequence};
+use shell::Shell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
+use task::{BusyPolicy, Task, TaskCommand, TaskSettings, TaskSpec};
+use watch::DirWatcher;
+
+const CONFIG_SETTLE_TIME: Duration = Duration::from_millis(200);
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Directory to watch for changes
+    /// Directory or file to watch for changes (repeatable) [default: current directory]
     #[arg(short, long)]
-    directory: PathBuf,
+    directory: Vec<PathBuf>,
+
+    /// Directory to watch without its subdirectories (repeatable)
+    #[arg(long, value_name = "DIRECTORY")]
+    shallow: Vec<PathBuf>,
+
+    /// Directory to run the command in [default: the first watched directory]
+    #[arg(short, long)]
+    workdir: Option<PathBuf>,
+
+    /// Directory that {relpath} and the paths passed to the command are relative to
+    /// [default: absolute paths, and the working directory for {relpath}]
+    #[arg(long, value_name = "DIRECTORY")]
+    relative_to: Option<PathBuf>,
 
-    /// Command to execute when changes are detected
+    /// Command to execute when changes are detected. Supports the placeholders
+    /// {path}, {relpath}, {dir}, {stem}, {ext}, {event} (for the first changed
+    /// file) and {changed} (all changed files), shell-quoted
     #[arg(short, long)]
-    command: String,
+    command: Option<String>,
+
+    /// Program and arguments to run directly, without a shell, instead of --command.
+    /// Placeholders are substituted unquoted, and a lone {changed} becomes one
+    /// argument per changed file.
+    #[arg(last = true, value_name = "ARGV", conflicts_with = "command")]
+    argv: Vec<String>,
+
+    /// Shell that runs commands [default: $SHELL, or /bin/sh]
+    #[arg(long, value_name = "PATH")]
+    shell: Option<String>,
+
+    /// Don't source the shell's rc file before each command
+    #[arg(long)]
+    no_rc: bool,
+
+    /// Don't start the shell as a login shell
+    #[arg(long)]
+    no_login: bool,
+
+    /// Source the rc file once and run commands in the resulting environment,
+    /// capturing it again when the rc files change
+    #[arg(long, conflicts_with = "no_rc")]
+    cache_env: bool,
+
+    /// Run a command only for changes matching a glob, as 'glob=command' (repeatable)
+    #[arg(long = "on", value_name = "GLOB=COMMAND")]
+    routes: Vec<Route>,
 
     /// File extensions to watch (comma-separated, e.g., "rs,toml,json")
     #[arg(short, long, value_delimiter = ',')]
     extensions: Vec<String>,
-}
 
-// Keep last few events for smarter debouncing
-struct EventBuffer {
-    events: VecDeque<Instant>,
-    window: Duration,
+    /// Only react to paths matching this glob, relative to the directory (repeatable, `!` negates)
+    #[arg(short, long)]
+    include: Vec<String>,
+
+    /// Ignore paths matching this glob, relative to the directory (repeatable, `!` negates)
+    #[arg(short = 'x', long)]
+    exclude: Vec<String>,
+
+    /// Don't honor .gitignore, .ignore, .watcherignore or global git excludes
+    #[arg(long)]
+    no_ignore: bool,
+
+    /// What to do with changes detected while the command is still running [default: queue]
+    #[arg(long, value_enum)]
+    on_busy: Option<BusyPolicy>,
+
+    /// Shorthand for `--on-busy restart` (for servers)
+    #[arg(short, long, conflicts_with = "on_busy")]
+    restart: bool,
+
+    /// Signal sent to the running command with `--on-busy signal` [default: SIGHUP]
+    #[arg(long)]
+    busy_signal: Option<Signal>,
+
+    /// How to stop a running command's process group: signals, each followed by
+    /// how long to wait, e.g. TERM,5s,INT,2s. Anything still running is killed.
+    /// [default: TERM,5s]
+    #[arg(long, value_name = "SEQUENCE")]
+    stop_sequence: Option<StopSequence>,
+
+    /// How changes are debounced into runs [default: trailing]
+    #[arg(long, value_enum)]
+    strategy: Option<Strategy>,
+
+    /// Debounce period, e.g. 250ms or 2s: the quiet time before running (trailing),
+    /// the time changes are ignored after a run (leading) or the minimum time
+    /// between runs (throttle) [default: 500ms]
+    #[arg(long, value_parser = parse_duration)]
+    debounce: Option<Duration>,
+
+    /// Changes less than this far apart form a burst, which only runs once it has
+    /// been quiet this long (trailing). Longer than --debounce, it lets bursts
+    /// from code generators settle while single saves still run quickly
+    /// [default: the debounce period]
+    #[arg(long, value_parser = parse_duration)]
+    window: Option<Duration>,
+
+    /// Run anyway once this long has passed since the first change of a batch,
+    /// even if changes keep arriving (e.g. 5s)
+    #[arg(long, value_parser = parse_duration)]
+    max_wait: Option<Duration>,
+
+    /// Also write the changed paths to the command's stdin, one per line or NUL-terminated
+    #[arg(long, value_enum)]
+    stdin_paths: Option<PathSeparator>,
+
+    /// Run the command once per changed file instead of once per batch
+    #[arg(long)]
+    per_file: bool,
+
+    /// Maximum number of files processed in parallel with --per-file [default: CPU count]
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Run the command once at startup, before any change
+    #[arg(long, conflicts_with = "postpone")]
+    initial_run: bool,
+
+    /// Wait for the first change before running, even if the configuration asks
+    /// for an initial run
+    #[arg(long)]
+    postpone: bool,
+
+    /// Start every line of command output with the time it was written
+    #[arg(long)]
+    timestamps: bool,
+
+    /// Configuration file with named tasks and shell profiles [default: nearest
+    /// watcher.toml]. A command given without --task ignores its tasks.
+    #[arg(long, conflicts_with = "no_config")]
+    config: Option<PathBuf>,
+
+    /// Don't look for a watcher.toml
+    #[arg(long)]
+    no_config: bool,
+
+    /// Only run this task from the configuration file (repeatable)
+    #[arg(short, long)]
+    task: Vec<String>,
+
+    /// Also reload the configuration file on SIGHUP
+    #[arg(long)]
+    reload_on_sighup: bool,
+
+    /// Exit after the first run, with the command's exit code
+    #[arg(long, conflicts_with = "max_runs")]
+    once: bool,
+
+    /// Exit after this many runs, with the exit code of the last one
+    #[arg(long, value_name = "N")]
+    max_runs: Option<usize>,
+
+    /// Exit as soon as a run succeeds
+    #[arg(long, conflicts_with = "until_failure")]
+    until_success: bool,
+
+    /// Exit as soon as a run fails, with its exit code
+    #[arg(long)]
+    until_failure: bool,
 }
 
-impl EventBuffer {
-    fn new(window: Duration) -> Self {
-        Self {
-            events: VecDeque::new(),
-            window,
+impl Cli {
+    // Merges a task from the config file with the command line, which takes precedence
+    fn resolve(&self, name: Option<String>, file: TaskConfig) -> Result<TaskSettings, String> {
+        let label = name.as_deref().unwrap_or("command line");
+
+        let directories: Vec<(PathBuf, bool)> =
+            if self.directory.is_empty() && self.shallow.is_empty() {
+                match file.directories {
+                    Some(directories) => directories
+                        .iter()
+                        .map(|root| (root.path().to_path_buf(), root.recursive()))
+                        .collect(),
+                    None => vec![(PathBuf::from("."), true)],
+                }
+            } else {
+                let recursive = self.directory.iter().map(|path| (path.clone(), true));
+                let shallow = self.shallow.iter().map(|path| (path.clone(), false));
+                recursive.chain(shallow).collect()
+            };
+        let canonicalize = |path: PathBuf| {
+            path.canonicalize()
+                .map_err(|e| format!("{}: {}: {}", label, path.display(), e))
+        };
+        let roots = directories
+            .into_iter()
+            .map(|(path, recursive)| {
+                let path = canonicalize(path)?;
+                Ok(WatchRoot {
+                    is_file: !path.is_dir(),
+                    path,
+                    recursive,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if roots.is_empty() {
+            return Err(format!("{}: no directory to watch", label));
         }
-    }
 
-    fn add_event(&mut self, now: Instant) {
-        // Remove old events outside the window
-        while let Some(time) = self.events.front() {
-            if now.duration_since(*time) > self.window {
-                self.events.pop_front();
+        let workdir = match self.workdir.clone().or(file.workdir) {
+            Some(workdir) => canonicalize(workdir)?,
+            None => roots[0].dir().to_path_buf(),
+        };
+        let relative_to = self
+            .relative_to
+            .clone()
+            .or(file.relative_to)
+            .map(canonicalize)
+            .transpose()?;
+
+        fn list<T: Clone>(cli: &[T], file: Option<Vec<T>>) -> Vec<T> {
+            if cli.is_empty() {
+                file.unwrap_or_default()
             } else {
-                break;
+                cli.to_vec()
             }
         }
-        self.events.push_back(now);
-    }
 
-    fn should_trigger(&self, min_quiet_period: Duration) -> bool {
-        if let Some(last_event) = self.events.back() {
-            // If we've had a quiet period and have some events, trigger
-            Instant::now().duration_since(*last_event) >= min_quiet_period
-                && !self.events.is_empty()
+        let command = if self.argv.is_empty() {
+            self.command
+                .clone()
+                .map(TaskCommand::Shell)
+                .or(file.command)
         } else {
-            false
+            Some(TaskCommand::Exec(self.argv.clone()))
+        };
+        if let Some(TaskCommand::Exec(args)) = &command {
+            if args.is_empty() {
+                return Err(format!("{}: empty command", label));
+            }
         }
+        let routes = list(&self.routes, file.on);
+        if command.is_none() && routes.is_empty() {
+            return Err(format!("{}: no command to execute", label));
+        }
+
+        let on_busy = if self.restart {
+            Some(BusyPolicy::Restart)
+        } else {
+            self.on_busy
+        };
+        let debounce = self
+            .debounce
+            .or(file.debounce)
+            .unwrap_or(Duration::from_millis(500));
+        let jobs = self.jobs.or(file.jobs).unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        });
+
+        Ok(TaskSettings {
+            name,
+            roots,
+            workdir,
+            relative_to,
+            command,
+            routes,
+            extensions: list(&self.extensions, file.extensions),
+            include: list(&self.include, file.include),
+            exclude: list(&self.exclude, file.exclude),
+            no_ignore: self.no_ignore || file.no_ignore.unwrap_or(false),
+            on_busy: on_busy.or(file.on_busy).unwrap_or(BusyPolicy::Queue),
+            busy_signal: self.busy_signal.or(file.busy_signal).unwrap_or_default(),
+            stop_sequence: self
+                .stop_sequence
+                .clone()
+                .or(file.stop_sequence)
+                .unwrap_or_default(),
+            strategy: self
+                .strategy
+                .or(file.strategy)
+                .unwrap_or(Strategy::Trailing),
+            debounce,
+            window: self.window.or(file.window).unwrap_or(debounce),
+            max_wait: self.max_wait.or(file.max_wait),
+            stdin_paths: self.stdin_paths.or(file.stdin_paths),
+            per_file: self.per_file || file.per_file.unwrap_or(false),
+            jobs,
+            timestamps: self.timestamps || file.timestamps.unwrap_or(false),
+            initial_run: !self.postpone && (self.initial_run || file.initial_run.unwrap_or(false)),
+            depends_on: file.depends_on.unwrap_or_default(),
+            then: file.then.unwrap_or_default(),
+        })
     }
 
-    fn clear(&mut self) {
-        self.events.clear();
+    // Whether --once, --max-runs or --until-* end the session after a run
+    fn should_exit(&self, runs: usize, code: i32) -> bool {
+        let max_runs = if self.once { Some(1) } else { self.max_runs };
+        max_runs.is_some_and(|max| runs >= max)
+            || (self.until_success && code == 0)
+            || (self.until_failure && code != 0)
     }
-}
 
-fn get_user_shell() -> (String, String) {
-    if let Ok(shell) = std::env::var("SHELL") {
-        let shell_name = std::path::Path::new(&shell)
-            .file_name()
-            .and_then(|name| name.to_str())
-            .unwrap_or("sh")
-            .to_string();
+    // Whether the command line describes a command of its own rather than
+    // overriding one of the tasks picked with --task
+    fn has_own_command(&self) -> bool {
+        self.task.is_empty()
+            && (self.command.is_some() || !self.argv.is_empty() || !self.routes.is_empty())
+    }
 
-        let rc_command = match shell_name.as_str() {
-            "zsh" => "source ~/.zshrc 2>/dev/null || true",
-            "bash" => "source ~/.bashrc 2>/dev/null || source ~/.bash_profile 2>/dev/null || true",
-            _ => "true",
+    // The configuration file to use, if any
+    fn config_path(&self) -> std::io::Result<Option<PathBuf>> {
+        match &self.config {
+            Some(path) => path.canonicalize().map(Some),
+            None if self.no_config => Ok(None),
+            None => Ok(config::find_config(&std::env::current_dir()?)),
+        }
+    }
+
+    // Builds the tasks to watch: the selected tasks from the config file, or a
+    // single task described by the command line alone
+    fn load_tasks(&self, config_path: Option<&Path>) -> Result<Vec<Task>, Box<dyn Error>> {
+        let Some(config_path) = config_path else {
+            if !self.task.is_empty() {
+                return Err("--task requires a watcher.toml configuration file".into());
+            }
+            let settings = self.resolve(None, TaskConfig::default())?;
+            let spec = Arc::new(TaskSpec::new(settings, 0, 0)?);
+            let plan = vec![Stage {
+                spec: spec.clone(),
+                after: Vec::new(),
+            }];
+            return Ok(vec![Task::new(spec, plan, Vec::new())]);
         };
 
-        (shell, rc_command.to_string())
-    } else {
-        ("/bin/sh".to_string(), "true".to_string())
+        let config = ConfigFile::load(config_path)?;
+        if config.tasks.is_empty() {
+            return Err(format!("{}: no tasks defined", config_path.display()).into());
+        }
+        for name in &self.task {
+            if !config.tasks.contains_key(name) {
+                return Err(format!("unknown task: {}", name).into());
+            }
+        }
+
+        // Command line overrides apply to the selected tasks, not to the other
+        // stages of their pipelines
+        let defaults = Cli::parse_from([env!("CARGO_PKG_NAME")]);
+        let name_width = config.tasks.keys().map(|name| name.len()).max();
+        let mut specs = BTreeMap::new();
+        for (color, (name, file)) in config.tasks.into_iter().enumerate() {
+            let cli = if self.task.is_empty() || self.task.contains(&name) {
+                self
+            } else {
+                &defaults
+            };
+            let settings = cli.resolve(Some(name.clone()), file)?;
+            let spec = TaskSpec::new(settings, color, name_width.unwrap_or(0))?;
+            specs.insert(name, Arc::new(spec));
+        }
+
+        let mut plans = BTreeMap::new();
+        for name in specs.keys() {
+            plans.insert(name.as_str(), pipeline::plan(name, &specs)?);
+        }
+
+        let names: Vec<&str> = if self.task.is_empty() {
+            plans.keys().copied().collect()
+        } else {
+            self.task.iter().map(String::as_str).collect()
+        };
+        if names.is_empty() {
+            return Err("no tasks to watch".into());
+        }
+
+        // Every task triggers on its own filters, but leaves changes that also
+        // trigger a pipeline including it to that pipeline
+        let tasks = names
+            .iter()
+            .map(|&name| {
+                let covered_by = pipeline::covering(name, &names, &plans)
+                    .into_iter()
+                    .map(|root| specs[root].clone())
+                    .collect();
+                Task::new(specs[name].clone(), plans[name].clone(), covered_by)
+            })
+            .collect();
+        Ok(tasks)
     }
 }
 
@@ -96,140 +439,387 @@ fn is_relevant_event(event_kind: &EventKind) -> bool {
     )
 }
 
-fn has_matching_extension(path: &std::path::Path, extensions: &[String]) -> bool {
-    if extensions.is_empty() {
-        return true;
+// Directories that never need an OS watch because nothing below them can trigger
+fn is_pruned(dir: &Path, tasks: &[Task], ignores: &mut Option<IgnoreFilter>) -> bool {
+    tasks
+        .iter()
+        .filter(|task| task.spec.covers(dir))
+        .all(|task| task.spec.is_pruned(dir, ignores))
+}
+
+// Every directory watched by at least one task. Files are seen through a
+// non-recursive watch of their directory, so atomic saves are noticed too.
+fn task_roots(tasks: &[Task]) -> Vec<(PathBuf, RecursiveMode)> {
+    let mut roots: Vec<(PathBuf, RecursiveMode)> = Vec::new();
+    for task in tasks {
+        for root in &task.spec.settings.roots {
+            let mode = if root.recursive && !root.is_file {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            let root = (root.dir().to_path_buf(), mode);
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
     }
+    roots
+}
 
-    path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| extensions.iter().any(|e| e == ext))
-        .unwrap_or(false)
+fn new_ignore_filter(tasks: &[Task], roots: &[(PathBuf, RecursiveMode)]) -> Option<IgnoreFilter> {
+    let honors_ignores = tasks.iter().any(|task| !task.spec.settings.no_ignore);
+    let dirs: Vec<PathBuf> = roots.iter().map(|(dir, _)| dir.clone()).collect();
+    honors_ignores.then(|| IgnoreFilter::new(&dirs))
 }
 
-fn process_output(reader: BufReader<impl std::io::Read>, is_stderr: bool) {
-    for line in reader.lines().filter_map(|line| line.ok()) {
-        if is_stderr {
-            eprintln!("\x1b[31m{}\x1b[0m", line);
-        } else {
-            println!("{}", line);
+fn print_watching(roots: &[(PathBuf, RecursiveMode)], directories: usize) {
+    println!(
+        "Watching {} ({} directories)",
+        roots
+            .iter()
+            .map(|(root, _)| format!("{:?}", root))
+            .collect::<Vec<_>>()
+            .join(", "),
+        directories
+    );
+}
+
+fn capture_env(shell: &mut Shell) {
+    match shell.capture_env() {
+        Ok(count) => println!("Captured shell environment ({} variables)", count),
+        Err(e) => eprintln!(
+            "\x1b[31mFailed to capture the shell environment: {}\x1b[0m",
+            e
+        ),
+    }
+}
+
+// Runs finished during this session, for the summary printed on exit
+struct Session {
+    started: Instant,
+    runs: usize,
+    failures: usize,
+}
+
+impl Session {
+    fn record(&mut self, code: i32) {
+        self.runs += 1;
+        if code != 0 {
+            self.failures += 1;
         }
     }
+
+    fn print_summary(&self) {
+        println!(
+            "\nSession: {} runs, {} succeeded, {} failed in {:.1?}",
+            self.runs,
+            self.runs - self.failures,
+            self.failures,
+            self.started.elapsed()
+        );
+    }
+}
+
+// The number of the last terminating signal received and not yet handled, or 0.
+// The terminal only sends SIGINT, SIGQUIT and SIGHUP to the watcher's own process
+// group, so the commands never see them. SIGHUP is left out when it reloads the
+// configuration.
+#[cfg(unix)]
+fn register_shutdown(hangup: bool) -> std::io::Result<Arc<AtomicUsize>> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+    let received = Arc::new(AtomicUsize::new(0));
+    let hangup = hangup.then_some(SIGHUP);
+    for signal in [SIGINT, SIGTERM, SIGQUIT].into_iter().chain(hangup) {
+        signal_hook::flag::register_usize(signal, Arc::clone(&received), signal as usize)?;
+    }
+    Ok(received)
+}
+
+#[cfg(not(unix))]
+fn register_shutdown(_hangup: bool) -> std::io::Result<Arc<AtomicUsize>> {
+    Ok(Arc::new(AtomicUsize::new(0)))
+}
+
+#[cfg(unix)]
+fn register_sighup() -> std::io::Result<Arc<AtomicBool>> {
+    let flag = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&flag))?;
+    Ok(flag)
+}
+
+#[cfg(not(unix))]
+fn register_sighup() -> std::io::Result<Arc<AtomicBool>> {
+    eprintln!("\x1b[31mSIGHUP is not supported on this platform\x1b[0m");
+    Ok(Arc::new(AtomicBool::new(false)))
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+// Stops every task's commands at the same time, waiting for their output, and
+// ends the session. Every exit after commands may have started goes through here,
+// as they run in process groups of their own and would outlive the watcher.
+// Another signal while they stop kills them right away.
+fn shut_down(tasks: &mut [Task], session: &Session, exit_code: i32, shutdown: &AtomicUsize) -> ! {
+    let groups: Vec<u32> = tasks.iter().flat_map(Task::process_groups).collect();
+    thread::scope(|scope| {
+        let stopping: Vec<_> = tasks
+            .iter_mut()
+            .filter(|task| task.is_running())
+            .map(|task| {
+                println!("{}Stopping command...", task.spec.prefix());
+                scope.spawn(|| task.stop())
+            })
+            .collect();
+
+        while !stopping.iter().all(|handle| handle.is_finished()) {
+            let signal = shutdown.swap(0, Ordering::Relaxed);
+            if signal != 0 {
+                println!("\nReceived signal {} again, killing commands", signal);
+                process::kill_groups(&groups);
+                let _ = std::io::stdout().flush();
+                std::process::exit(128 + signal as i32);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    });
+    session.print_summary();
+    let _ = std::io::stdout().flush();
+    std::process::exit(exit_code);
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
-    let (shell, rc_command) = get_user_shell();
+    // Before anything is spawned, so no command can be left behind
+    let shutdown = register_shutdown(!cli.reload_on_sighup)?;
+    let mut session = Session {
+        started: Instant::now(),
+        runs: 0,
+        failures: 0,
+    };
+
+    let config_file = match cli.config_path() {
+        Ok(path) => path,
+        Err(e) => Cli::command().error(clap::error::ErrorKind::Io, e).exit(),
+    };
+    // A command given on the command line runs on its own rather than in place of
+    // every task in the file, which then only provides shell profiles
+    let config_path = config_file.clone().filter(|_| !cli.has_own_command());
+    let mut tasks = match cli.load_tasks(config_path.as_deref()) {
+        Ok(tasks) => tasks,
+        Err(e) => Cli::command()
+            .error(clap::error::ErrorKind::InvalidValue, e)
+            .exit(),
+    };
+
+    let mut roots = task_roots(&tasks);
+    let mut ignores = new_ignore_filter(&tasks, &roots);
+    // Shell profiles are read once; changing them takes a restart
+    let profiles = match &config_file {
+        Some(path) => match ConfigFile::load(path) {
+            Ok(config) => config.shells,
+            Err(e) => Cli::command()
+                .error(clap::error::ErrorKind::InvalidValue, e)
+                .exit(),
+        },
+        None => BTreeMap::new(),
+    };
+    let mut shell = Shell::new(cli.shell.clone(), !cli.no_rc, !cli.no_login, &profiles);
+    let rc_files = shell.rc_files();
+    if cli.cache_env {
+        capture_env(&mut shell);
+    }
 
     let (tx, rx) = channel();
 
-    let mut watcher = notify::recommended_watcher(move |res| {
+    let watcher = notify::recommended_watcher(move |res| {
         if let Ok(event) = res {
             tx.send(event).unwrap();
         }
     })?;
 
-    watcher.watch(&cli.directory, RecursiveMode::Recursive)?;
+    let mut dir_watcher = DirWatcher::new(watcher);
+    for (root, mode) in &roots {
+        dir_watcher.watch_root(root, *mode, &mut |dir| is_pruned(dir, &tasks, &mut ignores))?;
+    }
+
+    if let Some(path) = &config_path {
+        println!("Using configuration: {}", path.display());
+        // Watch the file's directory rather than the file so atomic saves are seen
+        if let Some(dir) = path.parent() {
+            dir_watcher.watch_root(dir, RecursiveMode::NonRecursive, &mut |_| true)?;
+        }
+    }
 
-    println!("Watching directory: {:?}", cli.directory);
-    println!("Filtering for extensions: {:?}", cli.extensions);
-    println!("Using shell: {}", shell);
-    println!("Will execute command: {}", cli.command);
-    println!("Waiting for file changes...");
+    if cli.cache_env {
+        let mut rc_dirs: Vec<&Path> = rc_files.iter().filter_map(|f| f.parent()).collect();
+        rc_dirs.dedup();
+        for dir in rc_dirs {
+            if let Err(e) = dir_watcher.watch_root(dir, RecursiveMode::NonRecursive, &mut |_| true)
+            {
+                eprintln!("\x1b[31mFailed to watch {:?}: {}\x1b[0m", dir, e);
+            }
+        }
+    }
 
-    let shell_command = if cfg!(target_os = "windows") {
-        cli.command.clone()
+    let sighup = if cli.reload_on_sighup {
+        Some(register_sighup()?)
     } else {
-        format!("{rc_command}; {}", cli.command)
+        None
     };
 
-    // Configure debouncing
-    let mut event_buffer = EventBuffer::new(Duration::from_millis(1000));
-    let quiet_period = Duration::from_millis(500);
+    print_watching(&roots, dir_watcher.len());
+    for task in &tasks {
+        task.spec.print_summary();
+    }
+    println!("Using shell: {}", shell.program);
+
+    let mut started = false;
+    for task in &mut tasks {
+        if task.spec.settings.initial_run {
+            task.run_now(&shell);
+            started = true;
+        }
+    }
+    if !started {
+        println!("Waiting for file changes...");
+    }
+
+    // Editors often write the config in several steps, so wait until it settles
+    let mut config_changed_at: Option<Instant> = None;
+    let mut rc_changed_at: Option<Instant> = None;
+
+    let exit_code = loop {
+        let signal = shutdown.swap(0, Ordering::Relaxed);
+        if signal != 0 {
+            println!("\nReceived signal {}, shutting down", signal);
+            break 128 + signal as i32;
+        }
+
+        let mut reload = sighup
+            .as_ref()
+            .is_some_and(|flag| flag.swap(false, Ordering::Relaxed));
 
-    loop {
         match rx.recv_timeout(Duration::from_millis(100)) {
             Ok(event) => {
-                if !is_relevant_event(&event.kind) {
-                    continue;
+                let mut ignores_changed = false;
+                if let Some(ignores) = ignores.as_mut() {
+                    for path in &event.paths {
+                        ignores_changed |= ignores.reload_if_ignore_file(path);
+                    }
+                }
+                if ignores_changed {
+                    dir_watcher.rescan(&mut |dir| is_pruned(dir, &tasks, &mut ignores));
                 }
 
-                let matching_path = event
-                    .paths
-                    .iter()
-                    .any(|path| has_matching_extension(path, &cli.extensions));
+                dir_watcher.handle_event(&event, &mut |dir| is_pruned(dir, &tasks, &mut ignores));
 
-                if !matching_path {
-                    continue;
-                }
+                if is_relevant_event(&event.kind) {
+                    if config_path
+                        .as_ref()
+                        .is_some_and(|config| event.paths.contains(config))
+                    {
+                        config_changed_at = Some(Instant::now());
+                    }
+                    if cli.cache_env && event.paths.iter().any(|path| rc_files.contains(path)) {
+                        rc_changed_at = Some(Instant::now());
+                    }
 
-                event_buffer.add_event(Instant::now());
+                    let kind = ChangeKind::from_event(&event.kind);
+                    for task in &mut tasks {
+                        task.add_event(&event.paths, kind, &mut ignores);
+                    }
+                }
             }
-            Err(RecvTimeoutError::Timeout) => {
-                // Check if we should trigger based on the event buffer
-                if event_buffer.should_trigger(quiet_period) {
-                    println!("\nFile change detected!");
-                    println!("Executing command...\n");
-
-                    let mut child = if cfg!(target_os = "windows") {
-                        Command::new("cmd")
-                            .args(["/C", &shell_command])
-                            .current_dir(&cli.directory)
-                            .stdout(Stdio::piped())
-                            .stderr(Stdio::piped())
-                            .spawn()?
-                    } else {
-                        Command::new(&shell)
-                            .args(["-l", "-c", &shell_command])
-                            .current_dir(&cli.directory)
-                            .stdout(Stdio::piped())
-                            .stderr(Stdio::piped())
-                            .spawn()?
-                    };
-
-                    let stdout = child.stdout.take().expect("Failed to capture stdout");
-                    let stderr = child.stderr.take().expect("Failed to capture stderr");
-
-                    let stdout_thread = thread::spawn(move || {
-                        let reader = BufReader::new(stdout);
-                        process_output(reader, false);
-                    });
-
-                    let stderr_thread = thread::spawn(move || {
-                        let reader = BufReader::new(stderr);
-                        process_output(reader, true);
-                    });
-
-                    stdout_thread.join().unwrap();
-                    stderr_thread.join().unwrap();
-
-                    match child.wait() {
-                        Ok(status) => {
-                            if !status.success() {
-                                eprintln!(
-                                    "\n\x1b[31mCommand failed with status: {}\x1b[0m",
-                                    status
-                                );
-                                if let Some(code) = status.code() {
-                                    eprintln!("\x1b[31mExit code: {}\x1b[0m", code);
-                                }
-                            } else {
-                                println!("\n\x1b[32mCommand completed successfully\x1b[0m");
-                            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                eprintln!("\x1b[31mWatch error: channel disconnected\x1b[0m");
+                break 1;
+            }
+        }
+
+        if config_changed_at.is_some_and(|at| at.elapsed() >= CONFIG_SETTLE_TIME) {
+            config_changed_at = None;
+            reload = true;
+        }
+
+        if rc_changed_at.is_some_and(|at| at.elapsed() >= CONFIG_SETTLE_TIME) {
+            rc_changed_at = None;
+            println!("\nShell rc files changed");
+            capture_env(&mut shell);
+        }
+
+        if reload {
+            match cli.load_tasks(config_path.as_deref()) {
+                Ok(mut new_tasks) => {
+                    println!("\n\x1b[32mReloaded configuration\x1b[0m");
+
+                    // Runs in progress carry over to the task of the same name
+                    let old_names: Vec<Option<String>> = tasks
+                        .iter()
+                        .map(|task| task.spec.settings.name.clone())
+                        .collect();
+                    for mut old in tasks.drain(..) {
+                        let name = old.spec.settings.name.clone();
+                        match new_tasks.iter_mut().find(|t| t.spec.settings.name == name) {
+                            Some(task) => task.adopt_run(&mut old),
+                            None => old.stop(),
+                        }
+                    }
+                    tasks = new_tasks;
+
+                    let new_roots = task_roots(&tasks);
+                    for (root, mode) in roots.iter().filter(|root| !new_roots.contains(root)) {
+                        dir_watcher.unwatch_root(root, *mode);
+                    }
+                    ignores = new_ignore_filter(&tasks, &new_roots);
+                    for (root, mode) in new_roots.iter().filter(|root| !roots.contains(root)) {
+                        let result = dir_watcher.watch_root(root, *mode, &mut |dir| {
+                            is_pruned(dir, &tasks, &mut ignores)
+                        });
+                        if let Err(e) = result {
+                            eprintln!("\x1b[31mFailed to watch {:?}: {}\x1b[0m", root, e);
                         }
-                        Err(e) => eprintln!("\n\x1b[31mError waiting for command: {}\x1b[0m", e),
                     }
+                    dir_watcher.rescan(&mut |dir| is_pruned(dir, &tasks, &mut ignores));
+                    roots = new_roots;
 
-                    println!("\nWaiting for file changes...");
-                    event_buffer.clear();
+                    print_watching(&roots, dir_watcher.len());
+                    for task in &tasks {
+                        task.spec.print_summary();
+                    }
+
+                    // Only tasks added by the reload get an initial run
+                    for task in &mut tasks {
+                        let settings = &task.spec.settings;
+                        if settings.initial_run && !old_names.contains(&settings.name) {
+                            task.run_now(&shell);
+                        }
+                    }
                 }
+                Err(e) => eprintln!(
+                    "\n\x1b[31mInvalid configuration, keeping the previous one: {}\x1b[0m",
+                    e
+                ),
             }
-            Err(RecvTimeoutError::Disconnected) => {
-                eprintln!("\x1b[31mWatch error: channel disconnected\x1b[0m");
-                break;
+        }
+
+        let mut exit_code = None;
+        for task in &mut tasks {
+            if let Some(code) = task.reap(&shell) {
+                session.record(code);
+                if cli.should_exit(session.runs, code) {
+                    exit_code = Some(code);
+                    break;
+                }
+                task.print_waiting();
             }
+            task.tick(&shell);
         }
-    }
+        if let Some(code) = exit_code {
+            break code;
+        }
+    };
 
-    Ok(())
+    // No more events while the commands stop
+    drop(dir_watcher);
+    shut_down(&mut tasks, &session, exit_code, &shutdown);
 }
@@ -0,0 +1,72 @@
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Prefix colors, handed out to tasks in order; red is left for errors
+const COLORS: [&str; 6] = ["36", "33", "32", "35", "34", "96"];
+
+// How a task's command output is written to the terminal: optionally behind a
+// colored task name and a timestamp, one whole line per write so the output of
+// tasks running at the same time never mixes within a line
+#[derive(Clone, Debug, Default)]
+pub struct OutputFormat {
+    prefix: Option<String>,
+    timestamps: bool,
+}
+
+impl OutputFormat {
+    // `index` picks the color and `width` pads the name so prefixes line up
+    pub fn new(name: Option<&str>, index: usize, width: usize, timestamps: bool) -> Self {
+        let prefix = name.map(|name| {
+            let color = COLORS[index % COLORS.len()];
+            format!("\x1b[{}m{:<width$} |\x1b[0m ", color, name, width = width)
+        });
+        Self { prefix, timestamps }
+    }
+
+    pub fn write_line(&self, line: &str, is_stderr: bool) {
+        let mut text = String::with_capacity(line.len() + 32);
+        if let Some(prefix) = &self.prefix {
+            text.push_str(prefix);
+        }
+        if self.timestamps {
+            text.push_str(&timestamp());
+            text.push(' ');
+        }
+        if is_stderr {
+            text.push_str("\x1b[31m");
+            text.push_str(line);
+            text.push_str("\x1b[0m\n");
+            let _ = io::stderr().lock().write_all(text.as_bytes());
+        } else {
+            text.push_str(line);
+            text.push('\n');
+            let _ = io::stdout().lock().write_all(text.as_bytes());
+        }
+    }
+}
+
+// Local wall-clock time as HH:MM:SS.mmm
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let millis = now.subsec_millis();
+
+    #[cfg(unix)]
+    let (hours, minutes, seconds) = {
+        let secs = now.as_secs() as libc::time_t;
+        // localtime_r only writes to the tm we pass in
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe { libc::localtime_r(&secs, &mut tm) };
+        (tm.tm_hour, tm.tm_min, tm.tm_sec)
+    };
+
+    // Without localtime_r, fall back to UTC
+    #[cfg(not(unix))]
+    let (hours, minutes, seconds) = {
+        let secs = now.as_secs() % 86400;
+        (secs / 3600, secs / 60 % 60, secs % 60)
+    };
+
+    format!("{:02}:{:02}:{:02}.{:03}", hours, minutes, seconds, millis)
+}
@@ -0,0 +1,454 @@
+use crate::changes::ChangeSet;
+use crate::process::Signal;
+use crate::run::Run;
+use crate::shell::Shell;
+use crate::task::TaskSpec;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// One task in a pipeline, with the indices of the stages that must succeed first
+#[derive(Clone)]
+pub struct Stage {
+    pub spec: Arc<TaskSpec>,
+    pub after: Vec<usize>,
+}
+
+enum State {
+    Waiting,
+    Running(Run, Instant),
+    // With the stage's exit code, 0 on success
+    Finished(i32, Duration),
+    // Not run because a stage it comes after failed
+    Skipped,
+}
+
+impl State {
+    fn is_done(&self) -> bool {
+        matches!(self, State::Finished(..) | State::Skipped)
+    }
+
+    fn blocks_dependents(&self) -> bool {
+        match self {
+            State::Finished(code, _) => *code != 0,
+            State::Skipped => true,
+            State::Waiting | State::Running(..) => false,
+        }
+    }
+}
+
+// The stages started by one trigger. Every stage sees the same changes and starts
+// as soon as the stages it comes after have succeeded.
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    states: Vec<State>,
+    changes: ChangeSet,
+}
+
+impl Pipeline {
+    pub fn start(stages: Vec<Stage>, changes: ChangeSet, shell: &Shell) -> Self {
+        let mut pipeline = Self {
+            states: stages.iter().map(|_| State::Waiting).collect(),
+            stages,
+            changes,
+        };
+        pipeline.advance(shell);
+        pipeline
+    }
+
+    // Starts the stages that became ready and skips the ones that never will
+    fn advance(&mut self, shell: &Shell) {
+        let mut progressed = true;
+        while progressed {
+            progressed = false;
+
+            for index in 0..self.stages.len() {
+                if !matches!(self.states[index], State::Waiting) {
+                    continue;
+                }
+                let after = &self.stages[index].after;
+                if after.iter().any(|&i| self.states[i].blocks_dependents()) {
+                    self.states[index] = State::Skipped;
+                    progressed = true;
+                } else if after
+                    .iter()
+                    .all(|&i| matches!(self.states[i], State::Finished(0, _)))
+                {
+                    self.states[index] = self.start_stage(index, shell);
+                    progressed |= self.states[index].is_done();
+                }
+            }
+        }
+    }
+
+    // A stage whose jobs can't even be prepared fails without running
+    fn start_stage(&self, index: usize, shell: &Shell) -> State {
+        let spec = &self.stages[index].spec;
+        let jobs = match spec.jobs(shell, &self.changes) {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                eprintln!(
+                    "{}\x1b[31mError preparing command: {}\x1b[0m",
+                    spec.prefix(),
+                    e
+                );
+                return State::Finished(1, Duration::ZERO);
+            }
+        };
+        if jobs.is_empty() {
+            println!("{}No rule matches the changed files", spec.prefix());
+            return State::Finished(0, Duration::ZERO);
+        }
+
+        println!("{}Executing command...\n", spec.prefix());
+        let run = Run::start(jobs, spec.settings.jobs);
+        State::Running(run, Instant::now())
+    }
+
+    // Reaps finished stages and starts the next ones. Returns true once every stage is done.
+    pub fn poll(&mut self, shell: &Shell) -> bool {
+        for (stage, state) in self.stages.iter().zip(&mut self.states) {
+            if let State::Running(run, started) = state {
+                if run.poll() {
+                    run.report(&stage.spec.prefix());
+                    *state = State::Finished(run.exit_code(), started.elapsed());
+                }
+            }
+        }
+
+        self.advance(shell);
+        self.states.iter().all(State::is_done)
+    }
+
+    // 0 if every stage succeeded, otherwise the exit code of the first that failed
+    pub fn exit_code(&self) -> i32 {
+        self.states
+            .iter()
+            .find_map(|state| match state {
+                State::Finished(code, _) if *code != 0 => Some(*code),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn signal(&self, signal: Signal) -> io::Result<()> {
+        let results: Vec<io::Result<()>> = self
+            .states
+            .iter()
+            .filter_map(|state| match state {
+                State::Running(run, _) => Some(run.signal(signal)),
+                _ => None,
+            })
+            .collect();
+        results.into_iter().collect()
+    }
+
+    pub fn process_groups(&self) -> Vec<u32> {
+        self.states
+            .iter()
+            .flat_map(|state| match state {
+                State::Running(run, _) => run.process_groups().collect(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    // Stops the running stages at the same time, each with its own stop sequence.
+    // Returns the first error once all of them are done.
+    pub fn stop(self) -> io::Result<()> {
+        let stages = &self.stages;
+        thread::scope(|scope| {
+            let stopping: Vec<_> = stages
+                .iter()
+                .zip(self.states)
+                .filter_map(|(stage, state)| match state {
+                    State::Running(run, _) => {
+                        Some(scope.spawn(move || run.stop(&stage.spec.settings.stop_sequence)))
+                    }
+                    _ => None,
+                })
+                .collect();
+            let results: Vec<io::Result<()>> = stopping
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect();
+            results.into_iter().collect()
+        })
+    }
+
+    // Status of every stage; a single stage is already covered by its run's report
+    pub fn report(&self) {
+        if self.stages.len() < 2 {
+            return;
+        }
+
+        println!("\nPipeline status:");
+        for (stage, state) in self.stages.iter().zip(&self.states) {
+            let name = stage.spec.settings.name.as_deref().unwrap_or("command");
+            match state {
+                State::Finished(0, elapsed) => {
+                    println!("\x1b[32m✓ {} ({:.1?})\x1b[0m", name, elapsed)
+                }
+                State::Finished(_, elapsed) => {
+                    eprintln!("\x1b[31m✗ {} ({:.1?})\x1b[0m", name, elapsed)
+                }
+                State::Skipped => println!("- {} (skipped)", name),
+                State::Waiting | State::Running(..) => println!("… {}", name),
+            }
+        }
+    }
+}
+
+// Orders `root` and every task it reaches through `depends_on` and `then` so each
+// stage comes after the ones it needs
+pub fn plan(root: &str, specs: &BTreeMap<String, Arc<TaskSpec>>) -> Result<Vec<Stage>, String> {
+    let mut members: Vec<&str> = Vec::new();
+    let mut stack = vec![root];
+    while let Some(name) = stack.pop() {
+        if members.contains(&name) {
+            continue;
+        }
+        let spec = specs
+            .get(name)
+            .ok_or_else(|| format!("task '{}' refers to unknown task '{}'", root, name))?;
+        members.push(name);
+        let settings = &spec.settings;
+        stack.extend(settings.depends_on.iter().map(String::as_str));
+        stack.extend(settings.then.iter().map(String::as_str));
+    }
+
+    // A task runs after its own dependencies and after every task listing it in `then`
+    let prerequisites = |name: &str| -> Vec<&str> {
+        let mut before: Vec<&str> = specs[name]
+            .settings
+            .depends_on
+            .iter()
+            .map(String::as_str)
+            .collect();
+        for &other in &members {
+            let lists_it = specs[other].settings.then.iter().any(|then| then == name);
+            if lists_it && !before.contains(&other) {
+                before.push(other);
+            }
+        }
+        before
+    };
+
+    let mut order: Vec<&str> = Vec::new();
+    while order.len() < members.len() {
+        let next = members.iter().find(|name| {
+            !order.contains(name) && prerequisites(name).iter().all(|p| order.contains(p))
+        });
+        match next {
+            Some(name) => order.push(name),
+            None => {
+                let cycle: Vec<&str> = members
+                    .iter()
+                    .copied()
+                    .filter(|name| !order.contains(name))
+                    .collect();
+                return Err(format!(
+                    "dependency cycle between tasks: {}",
+                    cycle.join(", ")
+                ));
+            }
+        }
+    }
+
+    Ok(order
+        .iter()
+        .map(|&name| Stage {
+            spec: specs[name].clone(),
+            after: prerequisites(name)
+                .iter()
+                .filter_map(|p| order.iter().position(|name| name == p))
+                .collect(),
+        })
+        .collect())
+}
+
+// The tasks among `watched` that take the changes `name` matches too: those with
+// a larger pipeline including it, and the ones listed before it among tasks that
+// include each other, whose pipelines are then the same
+pub fn covering<'a>(
+    name: &str,
+    watched: &[&'a str],
+    plans: &BTreeMap<&str, Vec<Stage>>,
+) -> Vec<&'a str> {
+    let position = watched.iter().position(|&other| other == name);
+    watched
+        .iter()
+        .enumerate()
+        .filter(|&(index, &root)| {
+            let plan = &plans[root];
+            root != name
+                && plan
+                    .iter()
+                    .any(|stage| stage.spec.settings.name.as_deref() == Some(name))
+                && (plan.len() > plans[name].len() || Some(index) < position)
+        })
+        .map(|(_, &root)| root)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debounce::Strategy;
+    use crate::task::{BusyPolicy, TaskCommand, TaskSettings};
+    use std::path::PathBuf;
+
+    fn spec(name: &str, depends_on: &[&str], then: &[&str]) -> Arc<TaskSpec> {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let settings = TaskSettings {
+            name: Some(name.to_string()),
+            roots: Vec::new(),
+            workdir: PathBuf::from("/"),
+            relative_to: None,
+            command: Some(TaskCommand::Shell("true".to_string())),
+            routes: Vec::new(),
+            extensions: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            no_ignore: true,
+            on_busy: BusyPolicy::Queue,
+            busy_signal: Signal::default(),
+            stop_sequence: Default::default(),
+            strategy: Strategy::Trailing,
+            debounce: Duration::ZERO,
+            window: Duration::ZERO,
+            max_wait: None,
+            stdin_paths: None,
+            per_file: false,
+            jobs: 1,
+            timestamps: false,
+            initial_run: false,
+            depends_on: names(depends_on),
+            then: names(then),
+        };
+        Arc::new(TaskSpec::new(settings, 0, 0).unwrap())
+    }
+
+    fn specs(tasks: &[Arc<TaskSpec>]) -> BTreeMap<String, Arc<TaskSpec>> {
+        tasks
+            .iter()
+            .map(|spec| (spec.settings.name.clone().unwrap(), spec.clone()))
+            .collect()
+    }
+
+    fn name(stage: &Stage) -> &str {
+        stage.spec.settings.name.as_deref().unwrap()
+    }
+
+    // Stage names, each with the names of the stages it comes after
+    fn stages(plan: &[Stage]) -> Vec<(&str, Vec<&str>)> {
+        plan.iter()
+            .map(|stage| {
+                let after = stage.after.iter().map(|&i| name(&plan[i])).collect();
+                (name(stage), after)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plan_of_a_lone_task_is_itself() {
+        let specs = specs(&[spec("test", &[], &[]), spec("lint", &[], &[])]);
+        let plan = plan("test", &specs).unwrap();
+        assert_eq!(stages(&plan), [("test", vec![])]);
+    }
+
+    #[test]
+    fn plan_runs_dependencies_first_and_then_tasks_last() {
+        let specs = specs(&[
+            spec("compile", &[], &[]),
+            spec("test", &["compile"], &["deploy"]),
+            spec("deploy", &[], &[]),
+        ]);
+        let plan = plan("test", &specs).unwrap();
+        assert_eq!(
+            stages(&plan),
+            [
+                ("compile", vec![]),
+                ("test", vec!["compile"]),
+                ("deploy", vec!["test"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_orders_a_diamond() {
+        let specs = specs(&[
+            spec("fetch", &[], &[]),
+            spec("build", &["fetch"], &[]),
+            spec("docs", &["fetch"], &[]),
+            spec("release", &["build", "docs"], &[]),
+        ]);
+        let plan = plan("release", &specs).unwrap();
+        let stages = stages(&plan);
+        assert_eq!(stages.len(), 4);
+        assert_eq!(stages[0], ("fetch", vec![]));
+        assert_eq!(stages[3].0, "release");
+        assert_eq!(stages[3].1.len(), 2);
+    }
+
+    #[test]
+    fn plan_accepts_then_and_depends_on_describing_the_same_edge() {
+        let specs = specs(&[spec("a", &[], &["b"]), spec("b", &["a"], &[])]);
+        for root in ["a", "b"] {
+            let plan = plan(root, &specs).unwrap();
+            assert_eq!(stages(&plan), [("a", vec![]), ("b", vec!["a"])]);
+        }
+    }
+
+    #[test]
+    fn plan_rejects_a_cycle() {
+        let specs = specs(&[spec("a", &["b"], &[]), spec("b", &["a"], &[])]);
+        let error = plan("a", &specs).err().unwrap();
+        assert!(
+            error.starts_with("dependency cycle between tasks:"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn plan_rejects_an_unknown_task() {
+        let specs = specs(&[spec("a", &["missing"], &[])]);
+        let error = plan("a", &specs).err().unwrap();
+        assert_eq!(error, "task 'a' refers to unknown task 'missing'");
+    }
+
+    fn plans(specs: &BTreeMap<String, Arc<TaskSpec>>) -> BTreeMap<&str, Vec<Stage>> {
+        specs
+            .keys()
+            .map(|name| (name.as_str(), plan(name, specs).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn covering_leaves_changes_to_the_larger_pipeline() {
+        let specs = specs(&[spec("compile", &[], &[]), spec("test", &["compile"], &[])]);
+        let plans = plans(&specs);
+        let watched = ["compile", "test"];
+        assert_eq!(covering("compile", &watched, &plans), ["test"]);
+        assert!(covering("test", &watched, &plans).is_empty());
+    }
+
+    #[test]
+    fn covering_leaves_changes_of_tasks_including_each_other_to_the_first() {
+        let specs = specs(&[spec("a", &[], &["b"]), spec("b", &["a"], &[])]);
+        let plans = plans(&specs);
+        let watched = ["a", "b"];
+        assert!(covering("a", &watched, &plans).is_empty());
+        assert_eq!(covering("b", &watched, &plans), ["a"]);
+    }
+
+    #[test]
+    fn covering_ignores_pipelines_that_are_not_watched() {
+        let specs = specs(&[spec("compile", &[], &[]), spec("test", &["compile"], &[])]);
+        let plans = plans(&specs);
+        assert!(covering("compile", &["compile"], &plans).is_empty());
+    }
+}
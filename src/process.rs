@@ -0,0 +1,443 @@
+use crate::duration::parse_duration;
+use crate::output::OutputFormat;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// A POSIX signal, given by name (`HUP`, `SIGUSR1`) or number
+#[derive(Clone, Copy, Debug)]
+pub struct Signal(i32);
+
+impl Default for Signal {
+    fn default() -> Self {
+        #[cfg(unix)]
+        return Signal(libc::SIGHUP);
+        #[cfg(not(unix))]
+        return Signal(1);
+    }
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    #[cfg(unix)]
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if let Ok(number) = name.parse::<i32>() {
+            if !(1..=max_signal()).contains(&number) {
+                return Err(format!("no such signal: {}", number));
+            }
+            return Ok(Signal(number));
+        }
+
+        let upper = name.to_ascii_uppercase();
+        let signal = match upper.strip_prefix("SIG").unwrap_or(&upper) {
+            "HUP" => libc::SIGHUP,
+            "INT" => libc::SIGINT,
+            "QUIT" => libc::SIGQUIT,
+            "KILL" => libc::SIGKILL,
+            "USR1" => libc::SIGUSR1,
+            "USR2" => libc::SIGUSR2,
+            "ALRM" => libc::SIGALRM,
+            "TERM" => libc::SIGTERM,
+            "CONT" => libc::SIGCONT,
+            "STOP" => libc::SIGSTOP,
+            "TSTP" => libc::SIGTSTP,
+            "WINCH" => libc::SIGWINCH,
+            _ => return Err(format!("unknown signal: {}", name)),
+        };
+        Ok(Signal(signal))
+    }
+
+    #[cfg(not(unix))]
+    fn from_str(_name: &str) -> Result<Self, Self::Err> {
+        Err("signals are not supported on this platform".to_string())
+    }
+}
+
+// The highest signal number, counting real-time signals where there are any
+#[cfg(unix)]
+fn max_signal() -> i32 {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return libc::SIGRTMAX();
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    return 31;
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "signal {}", self.0)
+    }
+}
+
+// Signals sent to stop a command's process group, each followed by how long to
+// wait for the group to exit. Whatever outlives them all is killed.
+#[derive(Clone, Debug)]
+pub struct StopSequence(Vec<(Signal, Duration)>);
+
+impl Default for StopSequence {
+    fn default() -> Self {
+        #[cfg(unix)]
+        return StopSequence(vec![(Signal(libc::SIGTERM), Duration::from_secs(5))]);
+        #[cfg(not(unix))]
+        return StopSequence(Vec::new());
+    }
+}
+
+// Parses alternating signals and waits, e.g. `TERM,5s,INT,2s,KILL`
+impl FromStr for StopSequence {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut steps = Vec::new();
+        let mut parts = text.split(',').map(str::trim);
+        while let Some(signal) = parts.next() {
+            let signal: Signal = signal.parse()?;
+            let wait = match parts.next() {
+                Some(wait) => parse_duration(wait)?,
+                None => Duration::ZERO,
+            };
+            steps.push((signal, wait));
+        }
+        Ok(StopSequence(steps))
+    }
+}
+
+impl fmt::Display for StopSequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (signal, wait) in &self.0 {
+            write!(f, "{}, wait {:?}, ", signal, wait)?;
+        }
+        write!(f, "kill")
+    }
+}
+
+fn process_output(mut reader: BufReader<impl io::Read>, is_stderr: bool, output: &OutputFormat) {
+    let mut line = Vec::new();
+    while let Ok(read) = reader.read_until(b'\n', &mut line) {
+        if read == 0 {
+            break;
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        output.write_line(&String::from_utf8_lossy(&line), is_stderr);
+        line.clear();
+    }
+}
+
+// How long output may keep coming after a command exits before the run is
+// reported anyway. Processes it left in the background can hold its pipes open
+// for as long as they live; their output is still forwarded.
+const OUTPUT_GRACE_PERIOD: Duration = Duration::from_millis(200);
+
+// A spawned command whose output is forwarded from background threads
+pub struct RunningCommand {
+    child: Child,
+    output_threads: Vec<JoinHandle<()>>,
+    // Exit status of the command itself, and when it was noticed
+    exited: Option<(ExitStatus, Instant)>,
+}
+
+impl RunningCommand {
+    // Spawns the command, feeding it `input` on stdin if given
+    pub fn spawn(
+        mut command: Command,
+        input: Option<Vec<u8>>,
+        output: OutputFormat,
+    ) -> io::Result<Self> {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        if input.is_some() {
+            command.stdin(Stdio::piped());
+        }
+
+        // Own process group so the whole tree can be signalled at once
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+
+        let mut child = command.spawn()?;
+
+        let stdout = child.stdout.take().expect("Failed to capture stdout");
+        let stderr = child.stderr.take().expect("Failed to capture stderr");
+
+        let stdout_output = output.clone();
+        let stdout_thread = thread::spawn(move || {
+            let reader = BufReader::new(stdout);
+            process_output(reader, false, &stdout_output);
+        });
+
+        let stderr_thread = thread::spawn(move || {
+            let reader = BufReader::new(stderr);
+            process_output(reader, true, &output);
+        });
+
+        let mut output_threads = vec![stdout_thread, stderr_thread];
+
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+            // Commands that never read stdin just close the pipe early
+            output_threads.push(thread::spawn(move || {
+                let _ = stdin.write_all(&input);
+            }));
+        }
+
+        Ok(Self {
+            child,
+            output_threads,
+            exited: None,
+        })
+    }
+
+    // Returns the exit status once the command has finished and its output is
+    // flushed, or the grace period after its exit is over. Never blocks.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        let (status, exited_at) = match self.exited {
+            Some(exited) => exited,
+            None => match self.child.try_wait()? {
+                Some(status) => *self.exited.insert((status, Instant::now())),
+                None => return Ok(None),
+            },
+        };
+        if !self.output_drained() && exited_at.elapsed() < OUTPUT_GRACE_PERIOD {
+            return Ok(None);
+        }
+        self.finish_output();
+        Ok(Some(status))
+    }
+
+    fn output_drained(&self) -> bool {
+        self.output_threads.iter().all(JoinHandle::is_finished)
+    }
+
+    // Joins the output threads that are done and leaves the others to forward
+    // whatever processes still holding the pipes write
+    fn finish_output(&mut self) {
+        for handle in self.output_threads.drain(..) {
+            if handle.is_finished() {
+                handle.join().unwrap();
+            }
+        }
+    }
+
+    // Waits up to the grace period for the output to be flushed
+    fn wait_output(&mut self) {
+        let deadline = Instant::now() + OUTPUT_GRACE_PERIOD;
+        while !self.output_drained() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.finish_output();
+    }
+
+    // Whether the command and everything else in its process group have exited.
+    // Orphaned grandchildren still hold the group, so they count too.
+    #[cfg(unix)]
+    fn has_exited(&mut self) -> bool {
+        // A child that can't be waited for anymore is gone
+        let exited = self
+            .child
+            .try_wait()
+            .map_or(true, |status| status.is_some());
+        exited && !self.group_alive()
+    }
+
+    // Stops the commands and everything they spawned by going through `sequence`
+    // for all of them at once, so they share each wait instead of adding them up.
+    // Keeps going past commands that fail to stop and returns the first error.
+    pub fn stop_all(mut commands: Vec<Self>, sequence: &StopSequence) -> io::Result<()> {
+        let mut first_error = None;
+
+        #[cfg(unix)]
+        {
+            let mut alive: Vec<usize> = (0..commands.len()).collect();
+            alive.retain(|&i| !commands[i].has_exited());
+            for (signal, wait) in &sequence.0 {
+                if alive.is_empty() {
+                    break;
+                }
+                for &i in &alive {
+                    if let Err(e) = commands[i].signal_group(signal.0) {
+                        first_error.get_or_insert(e);
+                    }
+                }
+                // No deadline means waiting for as long as it takes
+                let deadline = Instant::now().checked_add(*wait);
+                loop {
+                    alive.retain(|&i| !commands[i].has_exited());
+                    if alive.is_empty() || deadline.is_some_and(|at| Instant::now() >= at) {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+            for &i in &alive {
+                if let Err(e) = commands[i].signal_group(libc::SIGKILL) {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = sequence;
+            for command in &mut commands {
+                if let Ok(None) = command.child.try_wait() {
+                    if let Err(e) = command.child.kill() {
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+        }
+
+        for mut command in commands {
+            if let Err(e) = command.child.wait() {
+                first_error.get_or_insert(e);
+            }
+            command.wait_output();
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    // The id of the command's process group, which it leads
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    // Delivers a signal to the command's whole process group
+    pub fn signal(&self, signal: Signal) -> io::Result<()> {
+        #[cfg(unix)]
+        return self.signal_group(signal.0);
+
+        #[cfg(not(unix))]
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot send {} on this platform", signal),
+        ))
+    }
+
+    #[cfg(unix)]
+    fn group_alive(&self) -> bool {
+        // Signal 0 only checks whether any process in the group is left
+        unsafe { libc::kill(-(self.child.id() as libc::pid_t), 0) == 0 }
+    }
+
+    #[cfg(unix)]
+    fn signal_group(&self, signal: libc::c_int) -> io::Result<()> {
+        // The child leads its own process group, so its pid is the group id
+        let result = unsafe { libc::kill(-(self.child.id() as libc::pid_t), signal) };
+        if result == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        // Everything in the group has already exited
+        if error.raw_os_error() == Some(libc::ESRCH) {
+            return Ok(());
+        }
+        Err(error)
+    }
+}
+
+// Kills the given process groups outright, e.g. when stopping them gracefully
+// takes too long for the user
+pub fn kill_groups(groups: &[u32]) {
+    #[cfg(unix)]
+    for &group in groups {
+        unsafe {
+            libc::kill(-(group as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = groups;
+}
+
+// Commands still running when dropped, e.g. while unwinding from a panic, are
+// killed with everything they spawned rather than left running on their own
+impl Drop for RunningCommand {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            #[cfg(unix)]
+            let _ = self.signal_group(libc::SIGKILL);
+            #[cfg(not(unix))]
+            let _ = self.child.kill();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn steps(sequence: &StopSequence) -> Vec<(i32, Duration)> {
+        sequence
+            .0
+            .iter()
+            .map(|(signal, wait)| (signal.0, *wait))
+            .collect()
+    }
+
+    #[test]
+    fn parses_signal_names_and_numbers() {
+        for name in ["TERM", "SIGTERM", "term", "15"] {
+            assert_eq!(name.parse::<Signal>().unwrap().0, libc::SIGTERM);
+        }
+        assert!("BOGUS".parse::<Signal>().is_err());
+    }
+
+    #[test]
+    fn rejects_signal_numbers_out_of_range() {
+        for number in ["0", "-5", "999"] {
+            assert!(number.parse::<Signal>().is_err(), "{}", number);
+        }
+        assert!("0,1s".parse::<StopSequence>().is_err());
+    }
+
+    #[test]
+    fn parses_stop_sequence() {
+        let sequence: StopSequence = "TERM,5s,INT,2s,KILL".parse().unwrap();
+        assert_eq!(
+            steps(&sequence),
+            [
+                (libc::SIGTERM, Duration::from_secs(5)),
+                (libc::SIGINT, Duration::from_secs(2)),
+                (libc::SIGKILL, Duration::ZERO),
+            ]
+        );
+    }
+
+    #[test]
+    fn stop_sequence_allows_spaces_and_a_missing_last_wait() {
+        let sequence: StopSequence = "INT, 500ms, TERM".parse().unwrap();
+        assert_eq!(
+            steps(&sequence),
+            [
+                (libc::SIGINT, Duration::from_millis(500)),
+                (libc::SIGTERM, Duration::ZERO),
+            ]
+        );
+    }
+
+    #[test]
+    fn stop_sequence_rejects_bad_parts() {
+        assert!("TERM,5x".parse::<StopSequence>().is_err());
+        assert!("5s,TERM".parse::<StopSequence>().is_err());
+        assert!("".parse::<StopSequence>().is_err());
+    }
+
+    #[test]
+    fn default_stop_sequence_waits_for_term() {
+        assert_eq!(
+            steps(&StopSequence::default()),
+            [(libc::SIGTERM, Duration::from_secs(5))]
+        );
+        assert_eq!(
+            StopSequence::default().to_string(),
+            format!("signal {}, wait 5s, kill", libc::SIGTERM)
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use crate::changes::ChangesFile;
+use crate::output::OutputFormat;
+use crate::process::{RunningCommand, Signal, StopSequence};
+use std::collections::VecDeque;
+use std::io;
+use std::process::{Command, ExitStatus};
+
+// A command to spawn, labelled with the file it was created for in per-file mode
+pub struct Job {
+    pub label: Option<String>,
+    pub command: Command,
+    pub input: Option<Vec<u8>>,
+    pub output: OutputFormat,
+    // Where WATCHER_CHANGED_FILE points, if the paths didn't fit the environment
+    pub changes_file: Option<ChangesFile>,
+}
+
+// One triggered run: a single job per batch, or one job per changed file
+// executed at most `max_parallel` at a time
+pub struct Run {
+    pending: VecDeque<Job>,
+    active: Vec<(Option<String>, RunningCommand)>,
+    results: Vec<(Option<String>, io::Result<ExitStatus>)>,
+    max_parallel: usize,
+    // Change lists of started jobs, removed when the run is dropped
+    changes_files: Vec<ChangesFile>,
+}
+
+impl Run {
+    // Jobs that fail to spawn, e.g. a mistyped program, count as failed jobs
+    pub fn start(jobs: Vec<Job>, max_parallel: usize) -> Self {
+        let mut run = Self {
+            pending: jobs.into(),
+            active: Vec::new(),
+            results: Vec::new(),
+            max_parallel: max_parallel.max(1),
+            changes_files: Vec::new(),
+        };
+        run.fill_slots();
+        run
+    }
+
+    fn fill_slots(&mut self) {
+        while self.active.len() < self.max_parallel {
+            let Some(job) = self.pending.pop_front() else {
+                break;
+            };
+            self.changes_files.extend(job.changes_file);
+            match RunningCommand::spawn(job.command, job.input, job.output) {
+                Ok(command) => self.active.push((job.label, command)),
+                Err(e) => self.results.push((job.label, Err(e))),
+            }
+        }
+    }
+
+    // Collects finished jobs and starts pending ones. Returns true once every job is done.
+    pub fn poll(&mut self) -> bool {
+        let mut index = 0;
+        while index < self.active.len() {
+            match self.active[index].1.try_wait().transpose() {
+                Some(result) => {
+                    let (label, _) = self.active.swap_remove(index);
+                    self.results.push((label, result));
+                }
+                None => index += 1,
+            }
+        }
+
+        self.fill_slots();
+        self.active.is_empty() && self.pending.is_empty()
+    }
+
+    // Signals every running job, returning the first error
+    pub fn signal(&self, signal: Signal) -> io::Result<()> {
+        let results: Vec<io::Result<()>> = self
+            .active
+            .iter()
+            .map(|(_, command)| command.signal(signal))
+            .collect();
+        results.into_iter().collect()
+    }
+
+    pub fn process_groups(&self) -> impl Iterator<Item = u32> + '_ {
+        self.active.iter().map(|(_, command)| command.id())
+    }
+
+    // Stops every running job and drops the ones not started yet
+    pub fn stop(mut self, sequence: &StopSequence) -> io::Result<()> {
+        self.pending.clear();
+        let commands = self.active.drain(..).map(|(_, command)| command).collect();
+        RunningCommand::stop_all(commands, sequence)
+    }
+
+    // 0 if every job exited successfully, otherwise the exit code of the first one
+    // that failed; only meaningful once poll returned true
+    pub fn exit_code(&self) -> i32 {
+        self.results
+            .iter()
+            .map(|(_, result)| match result {
+                Ok(status) => exit_code(status),
+                Err(_) => 1,
+            })
+            .find(|&code| code != 0)
+            .unwrap_or(0)
+    }
+
+    // Prints the outcome, starting each line with `prefix` to tell tasks apart
+    pub fn report(&self, prefix: &str) {
+        match self.results.as_slice() {
+            [(None, result)] => report_status(prefix, result),
+            results => report_summary(prefix, results),
+        }
+    }
+}
+
+// The status as a shell would report it, with 128 + N for a command killed by signal N
+fn exit_code(status: &ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+fn report_status(prefix: &str, result: &io::Result<ExitStatus>) {
+    match result {
+        Ok(status) => {
+            if !status.success() {
+                eprintln!(
+                    "\n{}\x1b[31mCommand failed with status: {}\x1b[0m",
+                    prefix, status
+                );
+                if let Some(code) = status.code() {
+                    eprintln!("{}\x1b[31mExit code: {}\x1b[0m", prefix, code);
+                }
+            } else {
+                println!("\n{}\x1b[32mCommand completed successfully\x1b[0m", prefix);
+            }
+        }
+        Err(e) => eprintln!("\n{}\x1b[31mError running command: {}\x1b[0m", prefix, e),
+    }
+}
+
+fn report_summary(prefix: &str, results: &[(Option<String>, io::Result<ExitStatus>)]) {
+    let mut sorted: Vec<_> = results.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+
+    println!();
+    let mut failures = 0;
+    for (label, result) in sorted {
+        let label = label.as_deref().unwrap_or("command");
+        match result {
+            Ok(status) if status.success() => println!("{}\x1b[32m✓ {}\x1b[0m", prefix, label),
+            Ok(status) => {
+                failures += 1;
+                eprintln!("{}\x1b[31m✗ {} ({})\x1b[0m", prefix, label, status);
+            }
+            Err(e) => {
+                failures += 1;
+                eprintln!("{}\x1b[31m✗ {} (error: {})\x1b[0m", prefix, label, e);
+            }
+        }
+    }
+
+    let succeeded = results.len() - failures;
+    if failures == 0 {
+        println!("{}\x1b[32m{} succeeded\x1b[0m", prefix, succeeded);
+    } else {
+        eprintln!(
+            "{}\x1b[31m{} succeeded, {} failed\x1b[0m",
+            prefix, succeeded, failures
+        );
+    }
+}
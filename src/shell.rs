@@ -0,0 +1,321 @@
+use crate::template::QuoteStyle;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// Printed by `env` between whatever the rc files write and the captured environment
+const ENV_MARKER: &[u8] = b"__WATCHER_ENV__=1\0";
+
+// Variables describing the capturing shell itself rather than the user's setup
+const UNCACHED_VARS: [&str; 4] = ["PWD", "OLDPWD", "SHLVL", "_"];
+
+// How to invoke a shell, given as `[shells.NAME]` in watcher.toml for shells
+// whose executable is called NAME. Paths starting with `~/` are relative to $HOME.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ShellProfile {
+    // Makes it a login shell; must come first for some shells
+    #[serde(default)]
+    pub login_flag: Option<String>,
+    // Always passed
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Passed when the rc files should be loaded, or when they should not
+    #[serde(default)]
+    pub rc_args: Vec<String>,
+    #[serde(default)]
+    pub no_rc_args: Vec<String>,
+    // Precedes the command line
+    #[serde(default = "default_command_flag")]
+    pub command_flag: String,
+    // Run before each command when the shell doesn't load its rc file by itself
+    #[serde(default)]
+    pub rc_command: Option<String>,
+    // Joins the rc command to the command line
+    #[serde(default = "default_separator")]
+    pub separator: String,
+    // Files the rc files consist of, watched with --cache-env
+    #[serde(default)]
+    pub rc_files: Vec<String>,
+    // How the shell runs the external `env` program
+    #[serde(default = "default_env_program")]
+    pub env_program: String,
+    // How placeholder values are quoted in command lines
+    #[serde(default)]
+    pub quoting: QuoteStyle,
+}
+
+fn default_command_flag() -> String {
+    "-c".to_string()
+}
+
+fn default_separator() -> String {
+    "; ".to_string()
+}
+
+fn default_env_program() -> String {
+    "env".to_string()
+}
+
+impl ShellProfile {
+    // Built-in profile for a shell, by executable name
+    pub fn builtin(name: &str) -> Self {
+        let mut profile = ShellProfile {
+            login_flag: Some("-l".to_string()),
+            args: Vec::new(),
+            rc_args: Vec::new(),
+            no_rc_args: Vec::new(),
+            command_flag: default_command_flag(),
+            rc_command: None,
+            separator: default_separator(),
+            rc_files: Vec::new(),
+            env_program: default_env_program(),
+            quoting: QuoteStyle::Posix,
+        };
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+
+        match name {
+            "bash" => {
+                profile.rc_command = Some(
+                    "source ~/.bashrc 2>/dev/null || source ~/.bash_profile 2>/dev/null || true"
+                        .to_string(),
+                );
+                profile.rc_files = strings(&["~/.bashrc", "~/.bash_profile", "~/.profile"]);
+            }
+            "zsh" => {
+                profile.rc_command = Some("source ~/.zshrc 2>/dev/null || true".to_string());
+                profile.rc_files = strings(&["~/.zshenv", "~/.zprofile", "~/.zshrc"]);
+            }
+            // Login shells read ~/.profile; interactive ones read $ENV, usually ~/.kshrc
+            "ksh" | "mksh" => {
+                profile.rc_command = Some("[ -r ~/.kshrc ] && . ~/.kshrc; true".to_string());
+                profile.rc_files = strings(&["~/.profile", "~/.kshrc"]);
+            }
+            "sh" | "dash" => profile.rc_files = strings(&["~/.profile"]),
+            // fish reads config.fish even for `-c`
+            "fish" => {
+                profile.no_rc_args = strings(&["--no-config"]);
+                profile.rc_files = strings(&["~/.config/fish/config.fish"]);
+                profile.quoting = QuoteStyle::Fish;
+            }
+            // nu only reads its config for `-c` when asked to
+            "nu" => {
+                let files = ["~/.config/nushell/env.nu", "~/.config/nushell/config.nu"];
+                profile.rc_args = vec![
+                    "--env-config".to_string(),
+                    files[0].to_string(),
+                    "--config".to_string(),
+                    files[1].to_string(),
+                ];
+                profile.rc_files = strings(&files);
+                profile.env_program = "^env".to_string();
+                profile.quoting = QuoteStyle::Nu;
+            }
+            // pwsh loads $PROFILE for -Command unless told otherwise
+            "pwsh" => {
+                profile.login_flag = Some("-Login".to_string());
+                profile.args = strings(&["-NoLogo", "-NonInteractive"]);
+                profile.no_rc_args = strings(&["-NoProfile"]);
+                profile.command_flag = "-Command".to_string();
+                profile.quoting = QuoteStyle::Powershell;
+                profile.rc_files = strings(&[
+                    "~/.config/powershell/Microsoft.PowerShell_profile.ps1",
+                    "~/.config/powershell/profile.ps1",
+                ]);
+            }
+            _ => {}
+        }
+        profile
+    }
+}
+
+// Shell used to run commands, and how it loads the user's rc files
+pub struct Shell {
+    pub program: String,
+    profile: ShellProfile,
+    rc: bool,
+    login: bool,
+    // Environment left behind by the rc files, once captured
+    env: Option<Vec<(OsString, OsString)>>,
+}
+
+impl Shell {
+    // `program` defaults to $SHELL, then /bin/sh. Its profile comes from
+    // `profiles` by executable name, or the built-in ones.
+    pub fn new(
+        program: Option<String>,
+        rc: bool,
+        login: bool,
+        profiles: &BTreeMap<String, ShellProfile>,
+    ) -> Self {
+        let program = program
+            .or_else(|| std::env::var("SHELL").ok())
+            .unwrap_or_else(|| "/bin/sh".to_string());
+
+        let name = Path::new(&program)
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .unwrap_or("sh");
+        let profile = profiles
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| ShellProfile::builtin(name));
+
+        Self {
+            program,
+            profile,
+            rc,
+            login,
+            env: None,
+        }
+    }
+
+    pub fn rc_files(&self) -> Vec<PathBuf> {
+        if !self.rc {
+            return Vec::new();
+        }
+        self.profile
+            .rc_files
+            .iter()
+            .map(|file| PathBuf::from(expand_home(file)))
+            .collect()
+    }
+
+    pub fn quote_style(&self) -> QuoteStyle {
+        self.profile.quoting
+    }
+
+    // The shell with its arguments up to the command line
+    fn invocation(&self, login: bool, rc: bool) -> Command {
+        let profile = &self.profile;
+        let mut command = Command::new(&self.program);
+        if let Some(flag) = profile.login_flag.as_ref().filter(|_| login) {
+            command.arg(flag);
+        }
+        command.args(&profile.args);
+        let rc_args = if rc {
+            &profile.rc_args
+        } else {
+            &profile.no_rc_args
+        };
+        command.args(rc_args.iter().map(|arg| expand_home(arg)));
+        command.arg(&profile.command_flag);
+        command
+    }
+
+    // Runs `command_line` through the shell. With a captured environment the rc
+    // files are not loaded again.
+    pub fn command(&self, command_line: &str) -> Command {
+        if cfg!(target_os = "windows") {
+            let mut command = Command::new("cmd");
+            command.args(["/C", command_line]);
+            return command;
+        }
+
+        if let Some(env) = &self.env {
+            let mut command = self.invocation(false, false);
+            command.env_clear().envs(env.iter().cloned());
+            command.arg(command_line);
+            return command;
+        }
+
+        let mut command = self.invocation(self.login, self.rc);
+        match self.profile.rc_command.as_ref().filter(|_| self.rc) {
+            Some(rc_command) => {
+                command.arg(format!(
+                    "{}{}{}",
+                    rc_command, self.profile.separator, command_line
+                ));
+            }
+            None => {
+                command.arg(command_line);
+            }
+        }
+        command
+    }
+
+    // Runs a program directly, in the captured environment if there is one
+    pub fn exec(&self, program: &str, args: &[String]) -> Command {
+        let mut command = Command::new(program);
+        command.args(args);
+        if let Some(env) = &self.env {
+            command.env_clear().envs(env.iter().cloned());
+        }
+        command
+    }
+
+    // Loads the rc files once and keeps the environment they produce for later
+    // commands. Returns the number of variables captured.
+    pub fn capture_env(&mut self) -> io::Result<usize> {
+        if cfg!(target_os = "windows") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "capturing the shell environment is not supported on this platform",
+            ));
+        }
+
+        let profile = &self.profile;
+        let env = &profile.env_program;
+        let mut lines = Vec::new();
+        if let Some(rc_command) = profile.rc_command.as_ref().filter(|_| self.rc) {
+            lines.push(rc_command.clone());
+        }
+        // Only the shell needs to be told how to run `env`; the inner one is run by `env` itself
+        lines.push(format!("{} -i __WATCHER_ENV__=1 env -0", env));
+        lines.push(format!("{} -0", env));
+
+        let mut command = self.invocation(self.login, self.rc);
+        command
+            .arg(lines.join(&profile.separator))
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit());
+
+        let output = command.output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{} exited with {}",
+                self.program, output.status
+            )));
+        }
+
+        let start = output
+            .stdout
+            .windows(ENV_MARKER.len())
+            .position(|window| window == ENV_MARKER)
+            .ok_or_else(|| io::Error::other("no environment in the shell's output"))?;
+
+        let env: Vec<(OsString, OsString)> = output.stdout[start + ENV_MARKER.len()..]
+            .split(|&byte| byte == 0)
+            .filter_map(|entry| {
+                let split = entry.iter().position(|&byte| byte == b'=')?;
+                Some((os_string(&entry[..split]), os_string(&entry[split + 1..])))
+            })
+            .filter(|(name, _)| !UNCACHED_VARS.iter().any(|var| name == var))
+            .collect();
+
+        let count = env.len();
+        self.env = Some(env);
+        Ok(count)
+    }
+}
+
+fn expand_home(path: &str) -> OsString {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest).into_os_string(),
+        _ => path.into(),
+    }
+}
+
+#[cfg(unix)]
+fn os_string(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes.to_vec())
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: &[u8]) -> OsString {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
@@ -0,0 +1,480 @@
+use crate::changes::{ChangeKind, ChangeSet, PathSeparator};
+use crate::config::Route;
+use crate::debounce::{EventBuffer, Strategy};
+use crate::filter::{compile_glob, PathFilter, WatchRoot};
+use crate::ignores::IgnoreFilter;
+use crate::output::OutputFormat;
+use crate::pipeline::{Pipeline, Stage};
+use crate::process::{Signal, StopSequence};
+use crate::run::Job;
+use crate::shell::Shell;
+use crate::template::{quote, Placeholders, QuoteStyle};
+use clap::ValueEnum;
+use globset::GlobMatcher;
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BusyPolicy {
+    /// Run once more after the current run, with all changes made meanwhile
+    Queue,
+    /// Stop the running command and start it again
+    Restart,
+    /// Drop changes made while the command runs
+    Ignore,
+    /// Send `--busy-signal` to the running command instead of rerunning it
+    Signal,
+}
+
+// A command as written by the user
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TaskCommand {
+    // A command line run through the shell
+    Shell(String),
+    // Program and arguments executed directly, without a shell
+    Exec(Vec<String>),
+}
+
+impl fmt::Display for TaskCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskCommand::Shell(command) => write!(f, "{}", command),
+            TaskCommand::Exec(args) => {
+                let quoted: Vec<_> = args
+                    .iter()
+                    .map(|arg| quote(arg, QuoteStyle::Posix))
+                    .collect();
+                write!(f, "{} (without shell)", quoted.join(" "))
+            }
+        }
+    }
+}
+
+// Everything needed to watch for and run one task, after merging the config
+// file, command line and defaults
+#[derive(Clone, Debug)]
+pub struct TaskSettings {
+    // None for the unnamed task described purely on the command line
+    pub name: Option<String>,
+    pub roots: Vec<WatchRoot>,
+    // Where commands run
+    pub workdir: PathBuf,
+    // Base of {relpath}; when set, the paths given to the command are relative to it too
+    pub relative_to: Option<PathBuf>,
+    // Runs for every batch; routes only run for the changes they match
+    pub command: Option<TaskCommand>,
+    pub routes: Vec<Route>,
+    pub extensions: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub no_ignore: bool,
+    pub on_busy: BusyPolicy,
+    pub busy_signal: Signal,
+    pub stop_sequence: StopSequence,
+    pub strategy: Strategy,
+    pub debounce: Duration,
+    pub window: Duration,
+    pub max_wait: Option<Duration>,
+    pub stdin_paths: Option<PathSeparator>,
+    pub per_file: bool,
+    pub jobs: usize,
+    pub timestamps: bool,
+    // Run once at startup instead of waiting for the first change
+    pub initial_run: bool,
+    // Tasks that must succeed before this one runs, and tasks to run after it succeeds
+    pub depends_on: Vec<String>,
+    pub then: Vec<String>,
+}
+
+// The parts of a task that don't change while it runs: its settings and compiled filters
+pub struct TaskSpec {
+    pub settings: TaskSettings,
+    filter: PathFilter,
+    // Compiled patterns of settings.routes, in the same order
+    routes: Vec<GlobMatcher>,
+    output: OutputFormat,
+}
+
+impl TaskSpec {
+    // `color` and `name_width` line up the output prefixes of all tasks
+    pub fn new(
+        settings: TaskSettings,
+        color: usize,
+        name_width: usize,
+    ) -> Result<Self, globset::Error> {
+        let filter = PathFilter::new(
+            &settings.roots,
+            &settings.extensions,
+            &settings.include,
+            &settings.exclude,
+        )?;
+        let routes = settings
+            .routes
+            .iter()
+            .map(|route| Ok(compile_glob(&route.pattern)?.compile_matcher()))
+            .collect::<Result<Vec<_>, globset::Error>>()?;
+        let output = OutputFormat::new(
+            settings.name.as_deref(),
+            color,
+            name_width,
+            settings.timestamps,
+        );
+
+        Ok(Self {
+            settings,
+            filter,
+            routes,
+            output,
+        })
+    }
+
+    // Prefix for status messages, so runs of different tasks can be told apart
+    pub fn prefix(&self) -> String {
+        match &self.settings.name {
+            Some(name) => format!("[{}] ", name),
+            None => String::new(),
+        }
+    }
+
+    pub fn print_summary(&self) {
+        let prefix = self.prefix();
+        let settings = &self.settings;
+
+        for root in &settings.roots {
+            let kind = match (root.is_file, root.recursive) {
+                (true, _) => "file",
+                (false, true) => "directory tree",
+                (false, false) => "directory",
+            };
+            println!("{}Watching {}: {:?}", prefix, kind, root.path);
+        }
+        println!(
+            "{}Filtering for extensions: {:?}",
+            prefix, settings.extensions
+        );
+        if !settings.include.is_empty() {
+            println!("{}Including paths: {:?}", prefix, settings.include);
+        }
+        if !settings.exclude.is_empty() {
+            println!("{}Excluding paths: {:?}", prefix, settings.exclude);
+        }
+        println!(
+            "{}Debounce: {:?} {:?} (window {:?})",
+            prefix, settings.strategy, settings.debounce, settings.window
+        );
+        println!("{}Working directory: {:?}", prefix, settings.workdir);
+        if let Some(max_wait) = settings.max_wait {
+            println!("{}Maximum wait: {:?}", prefix, max_wait);
+        }
+        if let Some(command) = &settings.command {
+            println!("{}Will execute command: {}", prefix, command);
+        }
+        for route in &settings.routes {
+            println!("{}On {}: {}", prefix, route.pattern, route.command);
+        }
+        match settings.on_busy {
+            BusyPolicy::Queue => {}
+            BusyPolicy::Restart => println!(
+                "{}Restarting the command on changes (stopping it with {})",
+                prefix, settings.stop_sequence
+            ),
+            BusyPolicy::Ignore => println!("{}Ignoring changes while the command runs", prefix),
+            BusyPolicy::Signal => println!(
+                "{}Sending {} to the command on changes",
+                prefix, settings.busy_signal
+            ),
+        }
+        if settings.per_file {
+            println!(
+                "{}Running once per changed file ({} in parallel)",
+                prefix, settings.jobs
+            );
+        }
+    }
+
+    fn honors_ignores(&self) -> bool {
+        !self.settings.no_ignore
+    }
+
+    pub fn covers(&self, path: &Path) -> bool {
+        self.filter.covers(path)
+    }
+
+    // Whether nothing below `dir` can ever trigger this task
+    pub fn is_pruned(&self, dir: &Path, ignores: &mut Option<IgnoreFilter>) -> bool {
+        self.filter.excludes_dir(dir)
+            || (self.honors_ignores() && ignores.as_mut().is_some_and(|i| i.is_ignored(dir)))
+    }
+
+    fn matches(&self, path: &Path, ignores: &mut Option<IgnoreFilter>) -> bool {
+        self.filter.matches(path)
+            && !(self.honors_ignores() && ignores.as_mut().is_some_and(|i| i.is_ignored(path)))
+    }
+
+    // Jobs for the task's own command plus every route matching part of the batch
+    pub fn jobs(&self, shell: &Shell, changes: &ChangeSet) -> io::Result<Vec<Job>> {
+        let mut jobs = Vec::new();
+
+        if let Some(command) = &self.settings.command {
+            self.add_jobs(&mut jobs, shell, command, changes, None)?;
+        }
+
+        for (route, matcher) in self.settings.routes.iter().zip(&self.routes) {
+            let matching = changes.filtered(|path| {
+                self.filter
+                    .relative(path)
+                    .is_some_and(|relative| matcher.is_match(relative))
+            });
+            if !matching.is_empty() {
+                self.add_jobs(&mut jobs, shell, &route.command, &matching, Some(route))?;
+            }
+        }
+
+        Ok(jobs)
+    }
+
+    // One job for the batch, or one per changed file in per-file mode
+    fn add_jobs(
+        &self,
+        jobs: &mut Vec<Job>,
+        shell: &Shell,
+        command: &TaskCommand,
+        changes: &ChangeSet,
+        route: Option<&Route>,
+    ) -> io::Result<()> {
+        let base = self.placeholder_base();
+
+        if !self.settings.per_file {
+            let placeholders = Placeholders::for_batch(base, changes);
+            let label = route.map(|route| route.pattern.clone());
+            jobs.push(self.build_job(shell, command, placeholders, changes, label)?);
+            return Ok(());
+        }
+
+        for (path, kind) in changes.iter() {
+            let mut single = ChangeSet::default();
+            single.add(path, kind);
+            let placeholders = Placeholders::for_path(base, path, kind);
+            let relative = path.strip_prefix(base).unwrap_or(path).display();
+            let label = match route {
+                Some(route) => format!("{} [{}]", relative, route.pattern),
+                None => relative.to_string(),
+            };
+            jobs.push(self.build_job(shell, command, placeholders, &single, Some(label))?);
+        }
+        Ok(())
+    }
+
+    fn placeholder_base(&self) -> &Path {
+        self.settings
+            .relative_to
+            .as_deref()
+            .unwrap_or(&self.settings.workdir)
+    }
+
+    // Renders the command for one run and attaches the changed paths to it
+    fn build_job(
+        &self,
+        shell: &Shell,
+        command: &TaskCommand,
+        placeholders: Placeholders,
+        changes: &ChangeSet,
+        label: Option<String>,
+    ) -> io::Result<Job> {
+        let mut command = match command {
+            TaskCommand::Shell(template) => {
+                shell.command(&placeholders.render(template, shell.quote_style()))
+            }
+            TaskCommand::Exec(args) => {
+                let args = placeholders.render_args(args);
+                let Some((program, args)) = args.split_first() else {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command"));
+                };
+                shell.exec(program, args)
+            }
+        };
+        command.current_dir(&self.settings.workdir);
+        let relative;
+        let changes = match &self.settings.relative_to {
+            Some(base) => {
+                relative = changes.relative_to(base);
+                &relative
+            }
+            None => changes,
+        };
+        let changes_file = changes.apply_env(&mut command)?;
+        let input = self
+            .settings
+            .stdin_paths
+            .map(|separator| changes.to_bytes(separator));
+
+        Ok(Job {
+            label,
+            command,
+            input,
+            output: self.output.clone(),
+            changes_file,
+        })
+    }
+}
+
+// A watched task: its spec, the stages a trigger runs and the state of the current run
+pub struct Task {
+    pub spec: Arc<TaskSpec>,
+    plan: Vec<Stage>,
+    // Watched tasks whose pipelines run this task too; changes they match are left to them
+    covered_by: Vec<Arc<TaskSpec>>,
+    event_buffer: EventBuffer,
+    running: Option<Pipeline>,
+}
+
+impl Task {
+    pub fn new(spec: Arc<TaskSpec>, plan: Vec<Stage>, covered_by: Vec<Arc<TaskSpec>>) -> Self {
+        let settings = &spec.settings;
+        let event_buffer = EventBuffer::new(settings.window, settings.strategy, settings.debounce);
+
+        Self {
+            spec,
+            plan,
+            covered_by,
+            event_buffer,
+            running: None,
+        }
+    }
+
+    pub fn add_event(
+        &mut self,
+        paths: &[PathBuf],
+        kind: ChangeKind,
+        ignores: &mut Option<IgnoreFilter>,
+    ) {
+        let matching_paths: Vec<&Path> = paths
+            .iter()
+            .map(PathBuf::as_path)
+            .filter(|path| {
+                self.spec.matches(path, ignores)
+                    && !self
+                        .covered_by
+                        .iter()
+                        .any(|spec| spec.matches(path, ignores))
+            })
+            .collect();
+
+        let dropped = self.running.is_some() && self.spec.settings.on_busy == BusyPolicy::Ignore;
+        if !matching_paths.is_empty() && !dropped {
+            self.event_buffer
+                .add_event(Instant::now(), &matching_paths, kind);
+        }
+    }
+
+    // Takes over another task's run in progress, e.g. across a configuration reload
+    pub fn adopt_run(&mut self, other: &mut Task) {
+        if let Some(pipeline) = other.running.take() {
+            self.running = Some(pipeline);
+        }
+    }
+
+    // Process groups of the commands running right now
+    pub fn process_groups(&self) -> Vec<u32> {
+        self.running
+            .as_ref()
+            .map(Pipeline::process_groups)
+            .unwrap_or_default()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(pipeline) = self.running.take() {
+            if let Err(e) = pipeline.stop() {
+                eprintln!("\x1b[31mError stopping command: {}\x1b[0m", e);
+            }
+        }
+    }
+
+    // Starts a run without any changes, e.g. at startup
+    pub fn run_now(&mut self, shell: &Shell) {
+        println!("\n{}Running initial command...", self.spec.prefix());
+        let pipeline = Pipeline::start(self.plan.clone(), ChangeSet::default(), shell);
+        self.running = Some(pipeline);
+    }
+
+    // Advances the current run; returns its exit code once it has finished
+    pub fn reap(&mut self, shell: &Shell) -> Option<i32> {
+        let pipeline = self.running.as_mut()?;
+        if !pipeline.poll(shell) {
+            return None;
+        }
+
+        pipeline.report();
+        let code = pipeline.exit_code();
+        self.running = None;
+        Some(code)
+    }
+
+    pub fn print_waiting(&self) {
+        println!("\n{}Waiting for file changes...", self.spec.prefix());
+    }
+
+    // Starts a new run if the debounced changes call for it
+    pub fn tick(&mut self, shell: &Shell) {
+        let prefix = self.spec.prefix();
+        let settings = &self.spec.settings;
+        let now = Instant::now();
+
+        // Check if we should trigger based on the event buffer
+        if !self.event_buffer.should_trigger(now) {
+            let max_wait = settings.max_wait;
+            let overdue = max_wait.is_some_and(|max| self.event_buffer.is_overdue(max, now));
+            if !overdue {
+                return;
+            }
+            if self.running.is_none() || settings.on_busy != BusyPolicy::Queue {
+                println!(
+                    "\n{}Changes still arriving after {:?}, force-flushing batch",
+                    prefix,
+                    max_wait.unwrap_or_default()
+                );
+            }
+        }
+
+        match self.running.take() {
+            Some(pipeline) if settings.on_busy == BusyPolicy::Restart => {
+                println!("\n{}File change detected, restarting command...", prefix);
+                if let Err(e) = pipeline.stop() {
+                    eprintln!("\x1b[31mError stopping command: {}\x1b[0m", e);
+                }
+            }
+            Some(pipeline) if settings.on_busy == BusyPolicy::Signal => {
+                let signal = settings.busy_signal;
+                println!(
+                    "\n{}File change detected, sending {} to command",
+                    prefix, signal
+                );
+                if let Err(e) = pipeline.signal(signal) {
+                    eprintln!("\x1b[31mError signalling command: {}\x1b[0m", e);
+                }
+                self.running = Some(pipeline);
+                self.event_buffer.mark_triggered(now);
+                return;
+            }
+            Some(pipeline) => {
+                // Queue: let the current run finish; the buffered changes trigger afterwards
+                self.running = Some(pipeline);
+                return;
+            }
+            None => println!("\n{}File change detected!", prefix),
+        }
+
+        let changes = self.event_buffer.mark_triggered(now);
+        self.running = Some(Pipeline::start(self.plan.clone(), changes, shell));
+    }
+}
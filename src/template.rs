@@ -0,0 +1,286 @@
+use crate::changes::{ChangeKind, ChangeSet};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::path::Path;
+
+const PLACEHOLDERS: [&str; 7] = ["path", "relpath", "dir", "stem", "ext", "changed", "event"];
+
+// Values substituted into `{placeholder}`s in a command. Single-path placeholders
+// refer to the current path, or the first changed path when running per batch.
+pub struct Placeholders<'a> {
+    base: &'a Path,
+    path: Option<(&'a Path, ChangeKind)>,
+    changed: Vec<&'a Path>,
+}
+
+impl<'a> Placeholders<'a> {
+    pub fn for_batch(base: &'a Path, changes: &'a ChangeSet) -> Self {
+        Self {
+            base,
+            path: changes.iter().next(),
+            changed: changes.paths().collect(),
+        }
+    }
+
+    pub fn for_path(base: &'a Path, path: &'a Path, kind: ChangeKind) -> Self {
+        Self {
+            base,
+            path: Some((path, kind)),
+            changed: vec![path],
+        }
+    }
+
+    // The placeholder's value, quoted for the shell if `style` is given
+    fn value(&self, name: &str, style: Option<QuoteStyle>) -> String {
+        let path = self.path.map(|(path, _)| path);
+        let quote = |value: Cow<'_, str>| match style {
+            Some(style) => quote(&value, style).into_owned(),
+            None => value.into_owned(),
+        };
+        let text = |value: Option<&std::ffi::OsStr>| {
+            value
+                .map(|v| quote(v.to_string_lossy()))
+                .unwrap_or_default()
+        };
+
+        match name {
+            "path" => text(path.map(Path::as_os_str)),
+            "relpath" => text(path.map(|p| p.strip_prefix(self.base).unwrap_or(p).as_os_str())),
+            "dir" => text(path.and_then(Path::parent).map(Path::as_os_str)),
+            "stem" => text(path.and_then(Path::file_stem)),
+            "ext" => text(path.and_then(Path::extension)),
+            "event" => self
+                .path
+                .map(|(_, kind)| kind.name().to_string())
+                .unwrap_or_default(),
+            "changed" => self
+                .changed
+                .iter()
+                .map(|path| quote(path.to_string_lossy()))
+                .collect::<Vec<_>>()
+                .join(" "),
+            _ => unreachable!("unknown placeholder {}", name),
+        }
+    }
+
+    // Replaces known placeholders such as `{path}`, leaving other braces (like
+    // `${VAR}`) alone. `{{path}}` produces a literal `{path}`.
+    pub fn render(&self, template: &str, style: QuoteStyle) -> String {
+        self.substitute(template, Some(style))
+    }
+
+    // Renders a program and arguments run without a shell: values are not quoted,
+    // and an argument that is exactly `{changed}` becomes one per changed path
+    pub fn render_args(&self, args: &[String]) -> Vec<String> {
+        let mut rendered = Vec::with_capacity(args.len());
+        for arg in args {
+            if arg == "{changed}" {
+                let changed = self.changed.iter();
+                rendered.extend(changed.map(|path| path.to_string_lossy().into_owned()));
+            } else {
+                rendered.push(self.substitute(arg, None));
+            }
+        }
+        rendered
+    }
+
+    fn substitute(&self, template: &str, style: Option<QuoteStyle>) -> String {
+        let mut output = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            output.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if let Some(name) = escaped_placeholder(rest) {
+                output.push('{');
+                output.push_str(name);
+                output.push('}');
+                rest = &rest[name.len() + 4..];
+            } else if let Some(name) = placeholder(rest) {
+                output.push_str(&self.value(name, style));
+                rest = &rest[name.len() + 2..];
+            } else {
+                output.push('{');
+                rest = &rest[1..];
+            }
+        }
+
+        output.push_str(rest);
+        output
+    }
+}
+
+// The known placeholder name if `text` starts with `{name}`
+fn placeholder(text: &str) -> Option<&str> {
+    let inner = text.strip_prefix('{')?;
+    let name = &inner[..inner.find('}')?];
+    PLACEHOLDERS.contains(&name).then_some(name)
+}
+
+fn escaped_placeholder(text: &str) -> Option<&str> {
+    let name = placeholder(text.strip_prefix('{')?)?;
+    text[name.len() + 2..].starts_with("}}").then_some(name)
+}
+
+// How a shell expects a single argument to be quoted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuoteStyle {
+    // 'it'\''s', for sh, bash, zsh and ksh
+    #[default]
+    Posix,
+    // 'it\'s', since fish also takes backslash escapes in single quotes
+    Fish,
+    // 'it''s'
+    Powershell,
+    // 'its' as long as there is no quote, otherwise the raw string r#'it's'#
+    Nu,
+}
+
+fn is_shell_safe(c: char, style: QuoteStyle) -> bool {
+    match style {
+        QuoteStyle::Posix | QuoteStyle::Fish => {
+            c.is_ascii_alphanumeric() || "_-+=.,/:@%".contains(c)
+        }
+        // `,` builds arrays and `@` splats in PowerShell; nu has its own operators
+        QuoteStyle::Powershell | QuoteStyle::Nu => c.is_ascii_alphanumeric() || "_-./".contains(c),
+    }
+}
+
+// Quotes a value so the shell passes it through as a single argument
+pub fn quote(value: &str, style: QuoteStyle) -> Cow<'_, str> {
+    if !value.is_empty() && value.chars().all(|c| is_shell_safe(c, style)) {
+        return Cow::Borrowed(value);
+    }
+
+    if cfg!(target_os = "windows") {
+        return Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")));
+    }
+    let quoted = match style {
+        QuoteStyle::Posix => format!("'{}'", value.replace('\'', "'\\''")),
+        QuoteStyle::Fish => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")),
+        // PowerShell also takes typographic single quotes as quotes
+        QuoteStyle::Powershell => {
+            let mut quoted = String::from("'");
+            for c in value.chars() {
+                if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+                    quoted.push(c);
+                }
+                quoted.push(c);
+            }
+            quoted.push('\'');
+            quoted
+        }
+        QuoteStyle::Nu if !value.contains('\'') => format!("'{}'", value),
+        QuoteStyle::Nu => {
+            let mut hashes = String::from("#");
+            while value.contains(&format!("'{}", hashes)) {
+                hashes.push('#');
+            }
+            format!("r{}'{}'{}", hashes, value, hashes)
+        }
+    };
+    Cow::Owned(quoted)
+}
+
+// Paths and quoting are Unix-style; Windows quotes for cmd instead
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn changes(paths: &[&str]) -> ChangeSet {
+        let mut changes = ChangeSet::default();
+        for path in paths {
+            changes.add(Path::new(path), ChangeKind::Modified);
+        }
+        changes
+    }
+
+    #[test]
+    fn render_substitutes_path_placeholders() {
+        let changes = changes(&["/project/src/main.rs"]);
+        let placeholders = Placeholders::for_batch(Path::new("/project"), &changes);
+        let rendered = placeholders.render(
+            "{path} {relpath} {dir} {stem} {ext} {event}",
+            QuoteStyle::Posix,
+        );
+        assert_eq!(
+            rendered,
+            "/project/src/main.rs src/main.rs /project/src main rs modified"
+        );
+    }
+
+    #[test]
+    fn render_quotes_values() {
+        let changes = changes(&["/project/it's here.txt", "/project/b.txt"]);
+        let placeholders = Placeholders::for_batch(Path::new("/project"), &changes);
+        assert_eq!(
+            placeholders.render("cat {changed}", QuoteStyle::Posix),
+            "cat /project/b.txt '/project/it'\\''s here.txt'"
+        );
+    }
+
+    #[test]
+    fn render_leaves_other_braces_alone() {
+        let changes = changes(&["/a.txt"]);
+        let placeholders = Placeholders::for_batch(Path::new("/"), &changes);
+        assert_eq!(
+            placeholders.render("${HOME} {unknown} {{path}} {path", QuoteStyle::Posix),
+            "${HOME} {unknown} {path} {path"
+        );
+    }
+
+    #[test]
+    fn render_without_changes_is_empty() {
+        let changes = ChangeSet::default();
+        let placeholders = Placeholders::for_batch(Path::new("/"), &changes);
+        assert_eq!(
+            placeholders.render("echo [{path}] [{changed}]", QuoteStyle::Posix),
+            "echo [] []"
+        );
+    }
+
+    #[test]
+    fn render_args_expands_a_lone_changed_into_arguments() {
+        let changes = changes(&["/p/a b.txt", "/p/c.txt"]);
+        let placeholders = Placeholders::for_batch(Path::new("/p"), &changes);
+        let args = ["fmt", "--", "{changed}", "x={relpath}"].map(String::from);
+        assert_eq!(
+            placeholders.render_args(&args),
+            ["fmt", "--", "/p/a b.txt", "/p/c.txt", "x=a b.txt"]
+        );
+    }
+
+    #[test]
+    fn quote_leaves_safe_values_alone() {
+        for style in [
+            QuoteStyle::Posix,
+            QuoteStyle::Fish,
+            QuoteStyle::Powershell,
+            QuoteStyle::Nu,
+        ] {
+            assert_eq!(quote("src/main.rs", style), "src/main.rs");
+            assert_eq!(quote("", style), "''");
+        }
+    }
+
+    #[test]
+    fn quote_escapes_quotes_per_shell() {
+        let value = r"it's a\b";
+        assert_eq!(quote(value, QuoteStyle::Posix), r"'it'\''s a\b'");
+        assert_eq!(quote(value, QuoteStyle::Fish), r"'it\'s a\\b'");
+        assert_eq!(quote(value, QuoteStyle::Powershell), r"'it''s a\b'");
+        assert_eq!(quote(value, QuoteStyle::Nu), r"r#'it's a\b'#");
+        assert_eq!(quote("a b", QuoteStyle::Nu), "'a b'");
+        assert_eq!(quote("x'#y", QuoteStyle::Nu), "r##'x'#y'##");
+    }
+
+    #[test]
+    fn quote_is_stricter_for_powershell_and_nu() {
+        assert_eq!(quote("a,b", QuoteStyle::Posix), "a,b");
+        assert_eq!(quote("a,b", QuoteStyle::Powershell), "'a,b'");
+        assert_eq!(quote("@a", QuoteStyle::Powershell), "'@a'");
+        assert_eq!(quote("a+b", QuoteStyle::Nu), "'a+b'");
+    }
+}
@@ -0,0 +1,179 @@
+use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Registers a non-recursive watch per directory so excluded trees such as
+// target/ or node_modules/ never consume OS watch descriptors
+pub struct DirWatcher<W: Watcher> {
+    watcher: W,
+    roots: Vec<(PathBuf, RecursiveMode)>,
+    watched: BTreeSet<PathBuf>,
+}
+
+impl<W: Watcher> DirWatcher<W> {
+    pub fn new(watcher: W) -> Self {
+        Self {
+            watcher,
+            roots: Vec::new(),
+            watched: BTreeSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.watched.len()
+    }
+
+    // Watches a root, and with RecursiveMode::Recursive every non-excluded directory below it
+    pub fn watch_root(
+        &mut self,
+        root: &Path,
+        mode: RecursiveMode,
+        is_excluded: &mut impl FnMut(&Path) -> bool,
+    ) -> notify::Result<()> {
+        if !self.watched.contains(root) {
+            self.watcher.watch(root, RecursiveMode::NonRecursive)?;
+            self.watched.insert(root.to_path_buf());
+        }
+        self.roots.push((root.to_path_buf(), mode));
+        if mode == RecursiveMode::Recursive {
+            self.watch_children(root, is_excluded);
+        }
+        Ok(())
+    }
+
+    // Stops watching a root and whatever below it no other root needs
+    pub fn unwatch_root(&mut self, root: &Path, mode: RecursiveMode) {
+        if let Some(index) = self.roots.iter().position(|(r, m)| r == root && *m == mode) {
+            self.roots.remove(index);
+        }
+
+        let stale: Vec<PathBuf> = self
+            .watched
+            .range(root.to_path_buf()..)
+            .take_while(|path| path.starts_with(root))
+            .filter(|path| !self.is_root(path) && !self.is_below_recursive_root(path))
+            .cloned()
+            .collect();
+
+        for path in stale {
+            let _ = self.watcher.unwatch(&path);
+            self.watched.remove(&path);
+        }
+    }
+
+    fn is_root(&self, path: &Path) -> bool {
+        self.roots.iter().any(|(root, _)| root == path)
+    }
+
+    fn is_below_recursive_root(&self, path: &Path) -> bool {
+        self.roots.iter().any(|(root, mode)| {
+            *mode == RecursiveMode::Recursive && path != root && path.starts_with(root)
+        })
+    }
+
+    fn watch_tree(&mut self, dir: &Path, is_excluded: &mut impl FnMut(&Path) -> bool) {
+        if !self.is_below_recursive_root(dir) || is_excluded(dir) {
+            return;
+        }
+
+        if !self.watched.contains(dir) {
+            if let Err(e) = self.watcher.watch(dir, RecursiveMode::NonRecursive) {
+                eprintln!("\x1b[31mFailed to watch {:?}: {}\x1b[0m", dir, e);
+                return;
+            }
+            self.watched.insert(dir.to_path_buf());
+        }
+        self.watch_children(dir, is_excluded);
+    }
+
+    fn watch_children(&mut self, dir: &Path, is_excluded: &mut impl FnMut(&Path) -> bool) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        // Symlinked directories are not followed to avoid cycles
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                self.watch_tree(&entry.path(), is_excluded);
+            }
+        }
+    }
+
+    fn unwatch_tree(&mut self, dir: &Path) {
+        let nested: Vec<PathBuf> = self
+            .watched
+            .range(dir.to_path_buf()..)
+            .take_while(|path| path.starts_with(dir))
+            .filter(|path| !self.is_root(path))
+            .cloned()
+            .collect();
+
+        for path in nested {
+            // The OS usually drops the watch itself once the directory is gone
+            let _ = self.watcher.unwatch(&path);
+            self.watched.remove(&path);
+        }
+    }
+
+    // Keeps watches in sync with directories being created, moved or deleted
+    pub fn handle_event(&mut self, event: &Event, is_excluded: &mut impl FnMut(&Path) -> bool) {
+        match event.kind {
+            EventKind::Create(CreateKind::Folder) | EventKind::Create(CreateKind::Any) => {
+                for path in &event.paths {
+                    if path.is_dir() {
+                        self.watch_tree(path, is_excluded);
+                    }
+                }
+            }
+            EventKind::Remove(RemoveKind::Folder) | EventKind::Remove(RemoveKind::Any) => {
+                for path in &event.paths {
+                    self.unwatch_tree(path);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                for path in &event.paths {
+                    self.unwatch_tree(path);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                for path in &event.paths {
+                    if path.is_dir() {
+                        self.watch_tree(path, is_excluded);
+                    }
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                for path in &event.paths {
+                    if path.is_dir() {
+                        self.watch_tree(path, is_excluded);
+                    } else {
+                        self.unwatch_tree(path);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Re-applies exclusions to the whole tree, e.g. after an ignore file changed
+    pub fn rescan(&mut self, is_excluded: &mut impl FnMut(&Path) -> bool) {
+        let excluded: Vec<PathBuf> = self
+            .watched
+            .iter()
+            .filter(|dir| !self.is_root(dir))
+            .filter(|dir| is_excluded(dir))
+            .cloned()
+            .collect();
+        for dir in excluded {
+            self.unwatch_tree(&dir);
+        }
+
+        for (root, mode) in self.roots.clone() {
+            if mode == RecursiveMode::Recursive {
+                self.watch_children(&root, is_excluded);
+            }
+        }
+    }
+}